@@ -0,0 +1,91 @@
+use error_stack::{Report, ResultExt};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite};
+
+use crate::{
+    app_error::AppError,
+    client::{AdminAction, AdminAudit, ClientId},
+    csv::CsvAckRecord,
+    engine::{EngineEvent, EngineSender, EventOutcome},
+};
+
+#[derive(Deserialize)]
+struct AdminInputRecord {
+    #[serde(rename = "type")]
+    action: AdminAction,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    operator: String,
+    reason: String,
+}
+
+/// Apply admin operations from `input` to the engine, writing each row's outcome to `output`
+/// as a `row, outcome, reason` csv row.
+///
+/// The first line of `input` must be the admin token, followed by csv rows under a `type, client, operator, reason`
+/// header. Input that fails authentication is rejected before any row is read.
+pub async fn process_admin_input(
+    engine: &EngineSender,
+    input: impl AsyncRead + Unpin + Send,
+    token: &str,
+    output: impl AsyncWrite + Unpin,
+) -> Result<(), Report<AppError>> {
+    let mut input = tokio::io::BufReader::new(input);
+    let mut token_line = String::new();
+    input
+        .read_line(&mut token_line)
+        .await
+        .change_context(AppError)?;
+    if !tokens_match(token_line.trim_end_matches(['\r', '\n']), token) {
+        return Err(Report::new(AppError).attach("Admin input failed authentication"));
+    }
+
+    let mut reader = csv_async::AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(input);
+    let mut records = reader.deserialize::<AdminInputRecord>();
+    let mut wtr = csv_async::AsyncSerializer::from_writer(output);
+
+    let mut row_index: usize = 0;
+    while let Some(row_result) = records.next().await {
+        let row = row_result
+            .change_context(AppError)
+            .attach_with(|| format!("Processing admin CSV row at index {row_index}"))?;
+        let outcome = engine
+            .send_event_with_reply(EngineEvent::Admin {
+                client_id: row.client_id,
+                action: row.action,
+                audit: AdminAudit {
+                    operator: row.operator,
+                    reason: row.reason,
+                },
+                timestamp: None,
+            })
+            .await?
+            .await
+            .change_context(AppError)
+            .attach("engine shutdown unexpectedly")?;
+        let ack = match outcome {
+            EventOutcome::Applied => CsvAckRecord::new(row_index, "applied", ""),
+            EventOutcome::Rejected(report) => {
+                CsvAckRecord::new(row_index, "rejected", &report.current_context().to_string())
+            }
+        };
+        wtr.serialize(&ack).await.change_context(AppError)?;
+        wtr.flush().await.change_context(AppError)?;
+        row_index += 1;
+    }
+
+    Ok(())
+}
+
+/// Compare without short-circuiting, so response timing doesn't leak how much of the token was right.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
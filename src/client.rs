@@ -1,17 +1,20 @@
-use std::collections::{HashMap, hash_map};
+use std::collections::{BTreeMap, BTreeSet, HashMap, hash_map};
 
 use error_stack::Report;
 
 use crate::{
     DecimalType,
     engine_error::EngineError,
-    transaction::{Transaction, TransactionId, TransactionKind},
+    fee::FeeSchedule,
+    ledger::{Account, ClientAccounts, Currency, DEFAULT_CURRENCY, Ledger, Posting, Transfer},
+    risk::{RecentWithdrawals, RiskRules},
+    transaction::{Timestamp, Transaction, TransactionId, TransactionKind, TransactionState},
 };
 
 pub type ClientId = u16;
 
 /// State of all clients in the system.
-#[derive(Default)]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct AllClientsState(HashMap<ClientId, ClientState>);
 
 impl AllClientsState {
@@ -27,12 +30,24 @@ impl AllClientsState {
                 return Err(Report::from(EngineError::ClientLocked(client_id)));
             }
         }
-        Ok(client_entry.or_insert_with(|| ClientState {
-            available: 0.into(),
-            held: 0.into(),
-            locked: false,
-            tx_lookup: HashMap::new(),
-        }))
+        Ok(client_entry.or_insert_with(ClientState::new))
+    }
+
+    /// Check `client_id` could be credited `tx` by `ClientState::deposit`, without creating it if missing,
+    /// so the sending side of a transfer is only applied if the receiving side will be too.
+    pub fn check_can_receive(
+        &self,
+        client_id: ClientId,
+        tx: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<(), Report<EngineError>> {
+        match self.0.get(&client_id) {
+            Some(client) if client.locked() => {
+                Err(Report::from(EngineError::ClientLocked(client_id)))
+            }
+            Some(client) => client.check_deposit(tx, policy).map(|_| ()),
+            None => ClientState::new().check_deposit(tx, policy).map(|_| ()),
+        }
     }
 
     /// Return the client if it exists and is unlocked.
@@ -51,120 +66,790 @@ impl AllClientsState {
         }
     }
 
+    pub fn get(&self, client_id: ClientId) -> Option<&ClientState> {
+        self.0.get(&client_id)
+    }
+
+    /// Return the client if it exists, locked or not, for administrative operations.
+    pub fn get_mut(&mut self, client_id: ClientId) -> Option<&mut ClientState> {
+        self.0.get_mut(&client_id)
+    }
+
+    /// Take ownership of clients from another state, e.g. when merging shards that own disjoint clients.
+    pub fn extend(&mut self, other: AllClientsState) {
+        self.0.extend(other.0);
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &ClientState)> {
         self.0.iter()
     }
 }
 
+/// Optional caps on amounts, enforced alongside the overflow checks whenever a balance changes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AmountLimits {
+    /// Largest amount a single deposit or withdrawal may be for.
+    pub max_transaction: Option<DecimalType>,
+    /// Largest total balance a deposit may take an account to, in the deposit's asset.
+    pub max_balance: Option<DecimalType>,
+}
+
+impl AmountLimits {
+    fn check_transaction(&self, tx: &Transaction) -> Result<(), Report<EngineError>> {
+        match self.max_transaction {
+            Some(limit) if tx.amount() > limit => {
+                Err(Report::from(EngineError::AmountOverLimit(tx.txid()))
+                    .attach(format!("Amount {} exceeds limit {limit}", tx.amount())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_balance(
+        &self,
+        total: DecimalType,
+        txid: TransactionId,
+    ) -> Result<(), Report<EngineError>> {
+        match self.max_balance {
+            Some(limit) if total > limit => Err(Report::from(EngineError::BalanceOverLimit(txid))
+                .attach(format!("Balance {total} would exceed limit {limit}"))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which transaction kinds can be disputed, and so resolved or charged back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DisputePolicy {
+    /// Only deposits and received transfers, disputing one holds its funds until resolved or charged back.
+    #[default]
+    DepositsOnly,
+    /// Withdrawals too, disputing one credits its amount as held until resolved or charged back.
+    DepositsAndWithdrawals,
+}
+
+impl DisputePolicy {
+    fn check_can_dispute(&self, tx: &Transaction) -> Result<(), Report<EngineError>> {
+        let can_dispute = match tx.kind() {
+            TransactionKind::Deposit { .. } | TransactionKind::TransferIn { .. } => true,
+            TransactionKind::Withdrawal { .. } => *self == DisputePolicy::DepositsAndWithdrawals,
+            // Disputed through the receiving side, see `ClientState::mirror_transfer_step`:
+            TransactionKind::TransferOut { .. } => false,
+        };
+        if !can_dispute {
+            return Err(Report::from(EngineError::TxCannotBeDisputed(tx.txid())));
+        }
+        Ok(())
+    }
+}
+
+/// Engine-wide rules for how client balances may change.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AccountPolicy {
+    pub limits: AmountLimits,
+    pub disputes: DisputePolicy,
+    pub fees: FeeSchedule,
+    pub risk: RiskRules,
+    pub deadlines: DisputeDeadlines,
+}
+
+/// Optional time limits on disputes in seconds, measured by the events' timestamps.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DisputeDeadlines {
+    /// How long after a transaction it can still be disputed.
+    pub dispute_window: Option<u64>,
+    /// How long a dispute can stay open before it's resolved without a chargeback.
+    pub chargeback_deadline: Option<u64>,
+}
+
+impl DisputeDeadlines {
+    pub fn from_days(dispute_window: Option<u32>, chargeback_deadline: Option<u32>) -> Self {
+        let seconds = |days: u32| u64::from(days) * 86_400;
+        DisputeDeadlines {
+            dispute_window: dispute_window.map(seconds),
+            chargeback_deadline: chargeback_deadline.map(seconds),
+        }
+    }
+
+    fn check_dispute_window(
+        &self,
+        tx: &Transaction,
+        now: Timestamp,
+    ) -> Result<(), Report<EngineError>> {
+        match (self.dispute_window, tx.timestamp()) {
+            (Some(window), Some(timestamp)) if now.saturating_sub(timestamp) > window => Err(
+                Report::from(EngineError::DisputeWindowExpired(tx.txid())).attach(format!(
+                    "Transaction at {timestamp} is {}s old at {now}, over the window of {window}s",
+                    now - timestamp
+                )),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A balance that failed checked arithmetic is a soft rejection of the transaction, never a panic.
+fn checked(
+    balance: Option<DecimalType>,
+    txid: TransactionId,
+) -> Result<DecimalType, Report<EngineError>> {
+    balance.ok_or_else(|| Report::from(EngineError::BalanceOverflow(txid)))
+}
+
+/// An operation from the support team, only accepted over the authenticated admin input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminAction {
+    /// Lift a chargeback or freeze lock.
+    Unlock,
+    /// Lock the client without a chargeback.
+    Freeze,
+    /// Permanently lock a client with no funds.
+    Close,
+}
+
+/// Who performed an admin action, and why.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AdminAudit {
+    pub operator: String,
+    pub reason: String,
+}
+
+/// A balance-changing event in a client's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Transfer,
+}
+
+/// A step of a dispute, as mirrored from the receiving side of a transfer onto its sending side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum DisputeStep {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl From<DisputeStep> for HistoryKind {
+    fn from(step: DisputeStep) -> Self {
+        match step {
+            DisputeStep::Dispute => HistoryKind::Dispute,
+            DisputeStep::Resolve => HistoryKind::Resolve,
+            DisputeStep::Chargeback => HistoryKind::Chargeback,
+        }
+    }
+}
+
+/// An applied event with the client's balances in its asset straight after it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    pub kind: HistoryKind,
+    pub txid: TransactionId,
+    #[serde(default)]
+    pub currency: Currency,
+    /// The amount the event acted on, e.g. only the disputed part for a partial dispute.
+    pub amount: DecimalType,
+    /// Fee charged alongside the event, negative when refunded.
+    #[serde(default)]
+    pub fee: DecimalType,
+    pub available: DecimalType,
+    pub held: DecimalType,
+}
+
+/// Why a client is locked.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum LockReason {
+    Chargeback(TransactionId),
+    Frozen(AdminAudit),
+    Closed(AdminAudit),
+}
+
+impl std::fmt::Display for LockReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockReason::Chargeback(txid) => write!(f, "chargeback of tx {txid}"),
+            LockReason::Frozen(audit) => {
+                write!(f, "frozen by {}: {}", audit.operator, audit.reason)
+            }
+            LockReason::Closed(audit) => {
+                write!(f, "closed by {}: {}", audit.operator, audit.reason)
+            }
+        }
+    }
+}
+
 /// State of a single client in the system.
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct ClientState {
-    available: DecimalType,
-    held: DecimalType,
-    locked: bool,
+    /// Accounts in each asset the client has transacted in, only changed by posting transfers to the ledger.
+    accounts: BTreeMap<Currency, ClientAccounts>,
+    lock: Option<LockReason>,
+    /// When the current lock was applied, the time of the chargeback or admin event that applied it.
+    #[serde(default)]
+    locked_at: Option<u64>,
     tx_lookup: HashMap<TransactionId, Transaction>,
+    /// Every admin action applied to the client, in order.
+    admin_log: Vec<(AdminAction, AdminAudit)>,
+    /// Every applied balance-changing event in order, only kept when the engine records history.
+    #[serde(default)]
+    history: Vec<HistoryEntry>,
+    /// Fees paid in each asset the client has been charged in, net of refunds.
+    #[serde(default)]
+    fees_paid: BTreeMap<Currency, DecimalType>,
+    /// Only kept when the velocity risk rule is set.
+    #[serde(default)]
+    recent_withdrawals: RecentWithdrawals,
+    /// When each dispute was opened, oldest first, only kept when there is a chargeback deadline.
+    /// Disputes since resolved or charged back are only dropped once they would have expired.
+    #[serde(default)]
+    open_disputes: BTreeSet<(Timestamp, TransactionId)>,
 }
 
 impl ClientState {
-    pub fn available(&self) -> DecimalType {
-        self.available
+    fn new() -> Self {
+        ClientState {
+            accounts: BTreeMap::new(),
+            lock: None,
+            locked_at: None,
+            tx_lookup: HashMap::new(),
+            admin_log: vec![],
+            history: vec![],
+            fees_paid: BTreeMap::new(),
+            recent_withdrawals: RecentWithdrawals::default(),
+            open_disputes: BTreeSet::new(),
+        }
+    }
+
+    /// Fees paid in `currency`, net of refunds.
+    pub fn fees_paid(&self, currency: &Currency) -> DecimalType {
+        self.fees_paid.get(currency).copied().unwrap_or_default()
     }
 
-    pub fn held(&self) -> DecimalType {
-        self.held
+    /// Whether the client has ever been charged a fee.
+    pub fn has_paid_fees(&self) -> bool {
+        !self.fees_paid.is_empty()
+    }
+
+    /// The client's accounts in `currency`, zero if it has never transacted in it.
+    pub fn accounts(&self, currency: &Currency) -> ClientAccounts {
+        self.accounts.get(currency).copied().unwrap_or_default()
+    }
+
+    /// The accounts in each asset ordered by currency, or zero in the default asset if there are none,
+    /// so every client has at least one balance to output.
+    pub fn balances(&self) -> impl Iterator<Item = (&Currency, ClientAccounts)> {
+        let none = self
+            .accounts
+            .is_empty()
+            .then_some((&DEFAULT_CURRENCY, ClientAccounts::default()));
+        self.accounts
+            .iter()
+            .map(|(currency, accounts)| (currency, *accounts))
+            .chain(none)
+    }
+
+    pub fn transaction(&self, txid: TransactionId) -> Option<&Transaction> {
+        self.tx_lookup.get(&txid)
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.tx_lookup.len()
+    }
+
+    /// Transactions with some part currently disputed.
+    pub fn open_dispute_count(&self) -> usize {
+        self.tx_lookup
+            .values()
+            .filter(|tx| tx.state() == TransactionState::Disputed)
+            .count()
+    }
+
+    pub fn recent_withdrawals(&self) -> &RecentWithdrawals {
+        &self.recent_withdrawals
+    }
+
+    /// Remember the withdrawal `txid` just applied, if the velocity risk rule needs it.
+    pub fn record_withdrawal(&mut self, risk: &RiskRules, txid: TransactionId) {
+        if let Some(tx) = self.tx_lookup.get(&txid) {
+            risk.record_withdrawal(&mut self.recent_withdrawals, self.tx_lookup.len() - 1, tx);
+        }
     }
 
-    pub fn total(&self) -> DecimalType {
-        self.held + self.available
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
     }
 
-    pub fn deposit(&mut self, tx: Transaction) {
-        self.available += tx.amount();
+    /// Append an event that was just applied, with the balances it left in its asset.
+    pub fn record_history(
+        &mut self,
+        kind: HistoryKind,
+        txid: TransactionId,
+        currency: Currency,
+        amount: DecimalType,
+        fee: DecimalType,
+    ) {
+        let accounts = self.accounts(&currency);
+        self.history.push(HistoryEntry {
+            kind,
+            txid,
+            currency,
+            amount,
+            fee,
+            available: accounts.available,
+            held: accounts.held,
+        });
+    }
+
+    /// Also the receiving side of a transfer, checked the same way. Any deposit fee is taken out of the deposit.
+    /// Returns what was posted to the ledger, withdrawals and the dispute steps likewise return theirs.
+    pub fn deposit(
+        &mut self,
+        mut tx: Transaction,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Posting, Report<EngineError>> {
+        let fee = self.check_deposit(&tx, policy)?;
+        let fees_paid = checked(self.fees_paid(tx.currency()).checked_add(fee), tx.txid())?;
+        let from = match tx.kind() {
+            TransactionKind::TransferIn { .. } => Account::Transfers,
+            _ => Account::Clearing,
+        };
+        let posting = Posting {
+            transfer: Transfer {
+                from,
+                to: Account::Available,
+                amount: tx.amount(),
+            },
+            fee: fee_transfer(Account::Available, Account::House, fee),
+        };
+        post(
+            &mut self.accounts,
+            ledger,
+            tx.currency(),
+            posting,
+            tx.txid(),
+        )?;
+        self.record_fee(&mut tx, fee, fees_paid);
         self.tx_lookup.insert(tx.txid(), tx);
+        Ok(posting)
+    }
+
+    /// Keep the fee charged for `tx` once it's posted, with the client's new total of fees paid.
+    fn record_fee(&mut self, tx: &mut Transaction, fee: DecimalType, fees_paid: DecimalType) {
+        if fee != DecimalType::ZERO {
+            tx.set_fee(fee);
+            self.fees_paid.insert(tx.currency().clone(), fees_paid);
+        }
+    }
+
+    /// Returns the fee the deposit would be charged, the balance limit applies to what's left after it.
+    fn check_deposit(
+        &self,
+        tx: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<DecimalType, Report<EngineError>> {
+        policy.limits.check_transaction(tx)?;
+        let fee = fee(tx, policy)?;
+        let total = checked(
+            self.accounts(tx.currency())
+                .total()
+                .checked_add(tx.amount()),
+            tx.txid(),
+        )?;
+        // Can't overflow, the fee is at most the amount:
+        policy.limits.check_balance(total - fee, tx.txid())?;
+        Ok(fee)
     }
 
     pub fn locked(&self) -> bool {
-        self.locked
+        self.lock.is_some()
+    }
+
+    pub fn lock_reason(&self) -> Option<&LockReason> {
+        self.lock.as_ref()
+    }
+
+    pub fn locked_at(&self) -> Option<u64> {
+        self.locked_at
     }
 
-    pub fn withdraw(&mut self, tx: Transaction) -> Result<(), Report<EngineError>> {
-        // Withdrawal should fail atomically if insufficient funds
-        if self.available < tx.amount() {
+    fn set_lock(&mut self, lock: Option<LockReason>, now: Timestamp) {
+        self.locked_at = lock.as_ref().map(|_| now);
+        self.lock = lock;
+    }
+
+    pub fn apply_admin_action(
+        &mut self,
+        client_id: ClientId,
+        action: AdminAction,
+        audit: AdminAudit,
+        now: Timestamp,
+    ) -> Result<(), Report<EngineError>> {
+        if let Some(LockReason::Closed(_)) = self.lock {
+            return Err(Report::from(EngineError::ClientClosed(client_id)));
+        }
+        let lock = match action {
+            AdminAction::Unlock => {
+                if self.lock.is_none() {
+                    return Err(Report::from(EngineError::ClientNotLocked(client_id)));
+                }
+                None
+            }
+            AdminAction::Freeze => {
+                if self.lock.is_some() {
+                    return Err(Report::from(EngineError::ClientLocked(client_id)));
+                }
+                Some(LockReason::Frozen(audit.clone()))
+            }
+            AdminAction::Close => {
+                // Closing with funds or open disputes in any asset would strand them:
+                if self.accounts.values().any(|accounts| {
+                    accounts.available != DecimalType::ZERO || accounts.held != DecimalType::ZERO
+                }) {
+                    return Err(Report::from(EngineError::ClientHasFunds(client_id)));
+                }
+                Some(LockReason::Closed(audit.clone()))
+            }
+        };
+        self.set_lock(lock, now);
+        self.admin_log.push((action, audit));
+        Ok(())
+    }
+
+    /// Check `tx` is within the limits and the client has the funds for it, fee included, returning the fee.
+    /// Withdrawal should fail atomically if insufficient funds.
+    pub fn check_can_withdraw(
+        &self,
+        tx: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<DecimalType, Report<EngineError>> {
+        policy.limits.check_transaction(tx)?;
+        let fee = fee(tx, policy)?;
+        let needed = checked(tx.amount().checked_add(fee), tx.txid())?;
+        if self.accounts(tx.currency()).available < needed {
             return Err(Report::from(EngineError::InsufficientFunds));
         }
-        self.available -= tx.amount();
+        Ok(fee)
+    }
+
+    /// Also the sending side of a transfer, checked the same way. Any fee is charged on top of the amount.
+    pub fn withdraw(
+        &mut self,
+        mut tx: Transaction,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Posting, Report<EngineError>> {
+        let fee = self.check_can_withdraw(&tx, policy)?;
+        let fees_paid = checked(self.fees_paid(tx.currency()).checked_add(fee), tx.txid())?;
+        let to = match tx.kind() {
+            TransactionKind::TransferOut { .. } => Account::Transfers,
+            _ => Account::Clearing,
+        };
+        let posting = Posting {
+            transfer: Transfer {
+                from: Account::Available,
+                to,
+                amount: tx.amount(),
+            },
+            fee: fee_transfer(Account::Available, Account::House, fee),
+        };
+        post(
+            &mut self.accounts,
+            ledger,
+            tx.currency(),
+            posting,
+            tx.txid(),
+        )?;
+        self.record_fee(&mut tx, fee, fees_paid);
         self.tx_lookup.insert(tx.txid(), tx);
-        Ok(())
+        Ok(posting)
     }
 
-    pub fn dispute_transaction(&mut self, txid: TransactionId) -> Result<(), Report<EngineError>> {
-        let tx = self
-            .tx_lookup
-            .get_mut(&txid)
-            .ok_or(EngineError::TxNotFound(txid))?;
-        tx.mark_disputed()?;
-        match tx.kind() {
-            TransactionKind::Deposit { amount } => {
-                // Not checking for >0 as disputes can allow user to go negative
-                self.available -= amount;
-                self.held += amount;
+    /// A dispute at `now`, which must be within the dispute window of the transaction.
+    pub fn dispute_transaction(
+        &mut self,
+        txid: TransactionId,
+        requested: Option<DecimalType>,
+        now: Timestamp,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Transfer, Report<EngineError>> {
+        let tx = disputable_tx_mut(&mut self.tx_lookup, txid)?;
+        // Checked before anything changes, so a rejection leaves the transaction and balances untouched:
+        policy.disputes.check_can_dispute(tx)?;
+        policy.deadlines.check_dispute_window(tx, now)?;
+        let amount = tx.dispute_amount(requested)?;
+        let from = match tx.kind() {
+            // Not checking for >0 as disputes can allow user to go negative
+            TransactionKind::Deposit { .. } | TransactionKind::TransferIn { .. } => {
+                Account::Available
             }
-            TransactionKind::Withdrawal { .. } => {
-                return Err(Report::from(EngineError::TxCannotBeDisputed(txid)));
+            // The client claims the funds were wrongly taken, so the platform provisionally credits them as held:
+            TransactionKind::Withdrawal { .. } => Account::Clearing,
+            TransactionKind::TransferOut { .. } => unreachable!("not disputable"),
+        };
+        let transfer = Transfer {
+            from,
+            to: Account::Held,
+            amount,
+        };
+        // Everything was checked above and marking can't fail, so balances only move for an accepted dispute:
+        post(&mut self.accounts, ledger, tx.currency(), transfer, txid)?;
+        tx.mark_disputed(amount, now);
+        track_dispute(&mut self.open_disputes, tx, policy);
+        Ok(transfer)
+    }
+
+    pub fn resolve_transaction(
+        &mut self,
+        txid: TransactionId,
+        requested: Option<DecimalType>,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Transfer, Report<EngineError>> {
+        let tx = disputable_tx_mut(&mut self.tx_lookup, txid)?;
+        policy.disputes.check_can_dispute(tx)?;
+        let amount = tx.settle_amount(requested, "resolved")?;
+        self.release_dispute(txid, amount, ledger)
+    }
+
+    /// Release `amount` of the dispute of `txid`, whatever the dispute policy.
+    fn release_dispute(
+        &mut self,
+        txid: TransactionId,
+        amount: DecimalType,
+        ledger: &mut Ledger,
+    ) -> Result<Transfer, Report<EngineError>> {
+        let tx = disputable_tx_mut(&mut self.tx_lookup, txid)?;
+        let to = match tx.kind() {
+            // The deposit stands, its funds are released:
+            TransactionKind::Deposit { .. } | TransactionKind::TransferIn { .. } => {
+                Account::Available
             }
+            // The withdrawal stands, the provisional credit is taken back:
+            TransactionKind::Withdrawal { .. } => Account::Clearing,
+            TransactionKind::TransferOut { .. } => unreachable!("not disputable"),
+        };
+        let transfer = Transfer {
+            from: Account::Held,
+            to,
+            amount,
+        };
+        post(&mut self.accounts, ledger, tx.currency(), transfer, txid)?;
+        tx.mark_resolved(amount);
+        Ok(transfer)
+    }
+
+    /// A chargeback at `now`, which dates the lock it applies.
+    pub fn chargeback_transaction(
+        &mut self,
+        txid: TransactionId,
+        requested: Option<DecimalType>,
+        now: Timestamp,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Posting, Report<EngineError>> {
+        let tx = disputable_tx_mut(&mut self.tx_lookup, txid)?;
+        policy.disputes.check_can_dispute(tx)?;
+        let amount = tx.settle_amount(requested, "charged back")?;
+        let to = match tx.kind() {
+            // The deposit is reversed, its funds go back to the card network:
+            TransactionKind::Deposit { .. } => Account::ChargebackLoss,
+            // The withdrawal is reversed, the provisional credit becomes the client's:
+            TransactionKind::Withdrawal { .. } => Account::Available,
+            // The transfer is reversed, its funds go back to the sending client by `mirror_transfer_step`:
+            TransactionKind::TransferIn { .. } => Account::Transfers,
+            TransactionKind::TransferOut { .. } => unreachable!("not disputable"),
+        };
+        // The transaction is reversed, so is its fee, by the first chargeback even if it's partial:
+        let refund = tx.chargeback_fee_refund();
+        let posting = Posting {
+            transfer: Transfer {
+                from: Account::Held,
+                to,
+                amount,
+            },
+            fee: fee_transfer(Account::House, Account::Available, refund),
+        };
+        post(&mut self.accounts, ledger, tx.currency(), posting, txid)?;
+        tx.mark_chargedback(amount);
+        refund_fee(&mut self.fees_paid, tx.currency(), refund);
+        self.set_lock(Some(LockReason::Chargeback(txid)), now);
+        Ok(posting)
+    }
+
+    /// Check a dispute step of a transfer can be mirrored onto this client's sending side, before it is applied to
+    /// the receiving side. A chargeback returns the funds to this client, so it can't be mirrored onto a closed one,
+    /// which must hold nothing.
+    pub fn check_can_mirror(
+        &self,
+        client_id: ClientId,
+        step: DisputeStep,
+    ) -> Result<(), Report<EngineError>> {
+        if step == DisputeStep::Chargeback && matches!(self.lock, Some(LockReason::Closed(_))) {
+            return Err(Report::from(EngineError::ClientClosed(client_id)));
         }
         Ok(())
     }
 
-    pub fn resolve_transaction(&mut self, txid: TransactionId) -> Result<(), Report<EngineError>> {
+    /// Apply a dispute step of a transfer, already applied to its receiving side, to this client's sending side,
+    /// whatever this client's lock, once `check_can_mirror` passed. Its state tracks the receiving side's, so `requested` settles the same amount.
+    /// Only a chargeback moves funds, returning them and the transfer fee to this client, and only it returns a posting.
+    pub fn mirror_transfer_step(
+        &mut self,
+        txid: TransactionId,
+        step: DisputeStep,
+        requested: Option<DecimalType>,
+        now: Timestamp,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Option<Posting>, Report<EngineError>> {
         let tx = self
             .tx_lookup
             .get_mut(&txid)
-            .ok_or_else(|| Report::from(EngineError::TxNotFound(txid)))?;
-        tx.mark_resolved()?;
-        match tx.kind() {
-            TransactionKind::Deposit { amount } => {
-                // Should be impossible that a held amount is less than the disputed amount:
-                if self.held < *amount {
-                    return Err(Report::from(EngineError::InternalError).attach(format!(
-                        "Held funds {} less than resolving dispute amount {} for txid {}",
-                        self.held, amount, txid
-                    )));
-                }
-                self.held -= amount;
-                self.available += amount;
+            .ok_or(EngineError::TxNotFound(txid))?;
+        match step {
+            DisputeStep::Dispute => {
+                let amount = tx.dispute_amount(requested)?;
+                tx.mark_disputed(amount, now);
+                // Opened at the same time as the receiving side's, so expires with it:
+                track_dispute(&mut self.open_disputes, tx, policy);
+                Ok(None)
             }
-            TransactionKind::Withdrawal { amount: _ } => {
-                return Err(Report::from(EngineError::TxCannotBeDisputed(txid)));
+            DisputeStep::Resolve => {
+                let amount = tx.settle_amount(requested, "resolved")?;
+                tx.mark_resolved(amount);
+                Ok(None)
+            }
+            DisputeStep::Chargeback => {
+                let amount = tx.settle_amount(requested, "charged back")?;
+                let refund = tx.chargeback_fee_refund();
+                let posting = Posting {
+                    transfer: Transfer {
+                        from: Account::Transfers,
+                        to: Account::Available,
+                        amount,
+                    },
+                    fee: fee_transfer(Account::House, Account::Available, refund),
+                };
+                post(&mut self.accounts, ledger, tx.currency(), posting, txid)?;
+                tx.mark_chargedback(amount);
+                refund_fee(&mut self.fees_paid, tx.currency(), refund);
+                Ok(Some(posting))
             }
         }
-        Ok(())
     }
 
-    pub fn chargeback_transaction(
+    /// Resolve every dispute open for longer than the chargeback deadline at `now`, oldest first, whatever
+    /// this client's lock. Returns the resolves that released held funds, with what each posted.
+    pub fn expire_disputes(
         &mut self,
-        txid: TransactionId,
-    ) -> Result<(), Report<EngineError>> {
-        let tx = self
-            .tx_lookup
-            .get_mut(&txid)
-            .ok_or_else(|| Report::from(EngineError::TxNotFound(txid)))?;
-        tx.mark_chargedback()?;
-        match tx.kind() {
-            TransactionKind::Deposit { amount } => {
-                // Should be impossible that a held amount is less than the disputed amount:
-                if self.held < *amount {
-                    return Err(Report::from(EngineError::InternalError).attach(format!(
-                        "Held funds {} less than resolving dispute amount {} for txid {}",
-                        self.held, amount, txid
-                    )));
-                }
-                self.held -= amount;
+        now: Timestamp,
+        policy: &AccountPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<Vec<(TransactionId, Posting)>, Report<EngineError>> {
+        let Some(deadline) = policy.deadlines.chargeback_deadline else {
+            return Ok(vec![]);
+        };
+        let mut resolved = vec![];
+        while let Some(&(disputed_at, txid)) = self.open_disputes.first() {
+            if now.saturating_sub(disputed_at) <= deadline {
+                break;
+            }
+            self.open_disputes.pop_first();
+            let tx = self
+                .tx_lookup
+                .get_mut(&txid)
+                .ok_or(EngineError::TxNotFound(txid))?;
+            // Settled since, or settled and disputed again later:
+            if tx.state() != TransactionState::Disputed || tx.disputed_at() != Some(disputed_at) {
+                continue;
             }
-            TransactionKind::Withdrawal { amount: _ } => {
-                return Err(Report::from(EngineError::TxCannotBeDisputed(txid)));
+            // Mirrors the receiving side, which expires by itself at the same time:
+            if let TransactionKind::TransferOut { .. } = tx.kind() {
+                tx.mark_resolved(tx.disputed());
+            } else {
+                // Released even if the policy has changed since, e.g. across a snapshot, or it'd stay held:
+                let disputed = tx.disputed();
+                let transfer = self.release_dispute(txid, disputed, ledger)?;
+                resolved.push((txid, transfer.into()));
             }
         }
-        self.locked = true;
-        Ok(())
+        Ok(resolved)
     }
 }
+
+/// Remember when `tx`'s dispute was opened, if disputes expire.
+fn track_dispute(
+    open_disputes: &mut BTreeSet<(Timestamp, TransactionId)>,
+    tx: &Transaction,
+    policy: &AccountPolicy,
+) {
+    if let (Some(_), Some(disputed_at)) = (policy.deadlines.chargeback_deadline, tx.disputed_at()) {
+        open_disputes.insert((disputed_at, tx.txid()));
+    }
+}
+
+/// The transaction a dispute step names, unless it's the sending side of a transfer,
+/// which is only disputed through its receiving side.
+fn disputable_tx_mut(
+    tx_lookup: &mut HashMap<TransactionId, Transaction>,
+    txid: TransactionId,
+) -> Result<&mut Transaction, Report<EngineError>> {
+    let tx = tx_lookup
+        .get_mut(&txid)
+        .ok_or(EngineError::TxNotFound(txid))?;
+    if let TransactionKind::TransferOut { .. } = tx.kind() {
+        return Err(Report::from(EngineError::TxCannotBeDisputed(txid)));
+    }
+    Ok(tx)
+}
+
+/// The fee `policy` charges for `tx`. A deposit's fee is capped at the deposit,
+/// so it never takes funds the client already had.
+fn fee(tx: &Transaction, policy: &AccountPolicy) -> Result<DecimalType, Report<EngineError>> {
+    let Some(fee) = policy.fees.fee_for(tx.kind()) else {
+        return Ok(DecimalType::ZERO);
+    };
+    let fee = checked(fee.charge(tx.amount()), tx.txid())?;
+    Ok(match tx.kind() {
+        TransactionKind::Deposit { .. } => fee.min(tx.amount()),
+        _ => fee,
+    })
+}
+
+/// The transfer moving a fee of `amount` between the client's available funds and the house account, if any.
+fn fee_transfer(from: Account, to: Account, amount: DecimalType) -> Option<Transfer> {
+    (amount != DecimalType::ZERO).then_some(Transfer { from, to, amount })
+}
+
+/// Take a fee just refunded off the client's fees paid.
+fn refund_fee(
+    fees_paid: &mut BTreeMap<Currency, DecimalType>,
+    currency: &Currency,
+    refund: DecimalType,
+) {
+    if let Some(paid) = fees_paid.get_mut(currency) {
+        *paid -= refund;
+    }
+}
+
+/// Post `posting` to the client's accounts in `currency`, only adding them once the post succeeds,
+/// so a rejected first transaction in an asset doesn't leave an empty balance behind.
+fn post(
+    accounts: &mut BTreeMap<Currency, ClientAccounts>,
+    ledger: &mut Ledger,
+    currency: &Currency,
+    posting: impl Into<Posting>,
+    txid: TransactionId,
+) -> Result<(), Report<EngineError>> {
+    let mut currency_accounts = accounts.get(currency).copied().unwrap_or_default();
+    ledger.post(&mut currency_accounts, currency, posting, txid)?;
+    accounts.insert(currency.clone(), currency_accounts);
+    Ok(())
+}
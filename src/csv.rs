@@ -1,5 +1,7 @@
+use std::sync::Arc;
+
 use error_stack::{Report, ResultExt};
-use futures::StreamExt;
+use futures::{StreamExt, stream::BoxStream};
 use serde::{Deserialize, Deserializer, Serialize};
 use tokio::io::AsyncWrite;
 
@@ -7,8 +9,11 @@ use crate::{
     DECIMAL_ACCURACY, DecimalType,
     app_error::AppError,
     client::{AllClientsState, ClientId},
-    engine::{EngineEvent, EngineHandle},
-    transaction::TransactionId,
+    engine::{EngineEvent, EngineSender, EventOutcome},
+    ledger::Currency,
+    output::{self, OutputOptions},
+    serialize_decimal,
+    transaction::{Timestamp, TransactionId},
 };
 
 const RECORD_TYPE_DEPOSIT: &str = "deposit";
@@ -16,16 +21,131 @@ const RECORD_TYPE_WITHDRAWAL: &str = "withdrawal";
 const RECORD_TYPE_DISPUTE: &str = "dispute";
 const RECORD_TYPE_RESOLVE: &str = "resolve";
 const RECORD_TYPE_CHARGEBACK: &str = "chargeback";
+const RECORD_TYPE_TRANSFER: &str = "transfer";
 
+/// An input row, deserialized straight from csv or converted from another input format.
 #[derive(Deserialize)]
-struct CsvInputRecord {
+pub struct InputRecord {
     #[serde(rename = "type", deserialize_with = "deserialize_lowercase")]
-    record_type: String,
+    pub record_type: String,
     #[serde(rename = "client")]
-    client_id: ClientId,
+    pub client_id: ClientId,
     #[serde(rename = "tx")]
-    txid: TransactionId,
-    amount: Option<DecimalType>,
+    pub txid: TransactionId,
+    /// As written, only parsed once the row is checked so its precision can be validated.
+    /// A missing column is the same as an empty one.
+    #[serde(default)]
+    pub amount: Option<String>,
+    /// The asset of a deposit, withdrawal or transfer, the default asset when missing or empty.
+    /// Ignored on disputes, resolves and chargebacks, which act on the original transaction's asset.
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// The receiving client of a transfer, whose `client` is the sending one. Ignored on other rows.
+    #[serde(default)]
+    pub to: Option<ClientId>,
+    /// When the transaction happened in unix seconds, the time the row is ingested when missing or empty.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+/// A data row read from any input format, before it's checked and converted to an event.
+pub enum InputRow {
+    Record {
+        record: InputRecord,
+        /// The row had more columns, or json fields, than `type, client, tx, amount, currency, to, timestamp`.
+        extra_columns: bool,
+    },
+    /// The row couldn't be read as a record at all, with why.
+    Malformed(String),
+}
+
+impl InputRow {
+    fn into_record(self) -> Option<InputRecord> {
+        match self {
+            InputRow::Record { record, .. } => Some(record),
+            InputRow::Malformed(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for InputRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputRow::Record { record, .. } => write!(
+                f,
+                "type={}, client_id={}, txid={}, amount={}",
+                record.record_type,
+                record.client_id,
+                record.txid,
+                record.amount.as_deref().unwrap_or_default()
+            ),
+            InputRow::Malformed(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// How rows that can't be applied as written are handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RowValidation {
+    /// Skip negative amounts and unknown types, abort on anything else malformed.
+    #[default]
+    Default,
+    /// Fail on any row the default would skip or abort on, and on amounts past `DECIMAL_ACCURACY` places
+    /// or on dispute, resolve and chargeback rows.
+    /// Use `validate_rows` first to fail before any row is applied.
+    Strict,
+    /// Skip and report every row the default would skip or abort on.
+    Lenient,
+}
+
+/// How amounts with more than `DECIMAL_ACCURACY` decimal places are read. Every policy but `Keep` brings them
+/// to it as they're read, so stored balances are always exactly what's output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PrecisionPolicy {
+    /// Store the excess digits, only rounding them at output. The fixed-point backend rounds half to even
+    /// as it parses, so it stores `Round`'s balances either way.
+    #[default]
+    Keep,
+    /// Skip the row, as `excess_precision`.
+    Reject,
+    /// Round half to even.
+    Round,
+    /// Drop the excess digits, rounding towards zero.
+    Truncate,
+}
+
+impl PrecisionPolicy {
+    /// Parse `amount` to at most `DECIMAL_ACCURACY` places unless keeping it, `Reject` leaving the caller to skip
+    /// excess precision.
+    fn parse(self, amount: &str) -> Result<DecimalType, String> {
+        let amount = amount.trim();
+        let accuracy = DECIMAL_ACCURACY as usize;
+        let truncated;
+        let amount = match amount.split_once('.') {
+            // Truncated as text, as the fixed-point parser rounds anything past `DECIMAL_ACCURACY` away:
+            Some((whole, fraction))
+                if self == PrecisionPolicy::Truncate
+                    && fraction.len() > accuracy
+                    && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                truncated = format!("{whole}.{}", &fraction[..accuracy]);
+                truncated.as_str()
+            }
+            _ => amount,
+        };
+        let parsed = parse_amount(amount)?;
+        Ok(match self {
+            PrecisionPolicy::Keep => parsed,
+            _ => parsed.round_dp(DECIMAL_ACCURACY),
+        })
+    }
+}
+
+/// How input rows are checked and read, shared by every input format.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InputOptions {
+    pub validation: RowValidation,
+    pub precision: PrecisionPolicy,
 }
 
 #[derive(Serialize)]
@@ -33,13 +153,89 @@ struct CsvInputRecord {
 pub struct CsvOutputRecord {
     #[serde(rename = "client")]
     client_id: ClientId,
+    /// Only a column when some client holds a named asset, empty for the default asset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(test, serde(default))]
+    currency: Option<Currency>,
     #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
     available: DecimalType,
     #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
     held: DecimalType,
     #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
     total: DecimalType,
-    locked: bool,
+    locked: LockedColumn,
+    /// Only a column when some client was charged a fee, the fees paid in the row's asset net of refunds.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::serialize_optional_decimal"
+    )]
+    #[cfg_attr(
+        test,
+        serde(default, deserialize_with = "crate::deserialize_optional_decimal")
+    )]
+    fees: Option<DecimalType>,
+}
+
+/// The `locked` output column: the brief's bool, or why the client is locked when lock reasons are requested.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq, Deserialize))]
+#[serde(untagged)]
+pub enum LockedColumn {
+    Locked(bool),
+    /// Empty when not locked.
+    Reason(String),
+}
+
+/// Written back to a submitter for every acknowledged row.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq, Deserialize))]
+pub struct CsvAckRecord {
+    row: usize,
+    outcome: String,
+    reason: String,
+}
+
+/// A row that was not applied, with a stable reason code.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq, Deserialize))]
+pub struct CsvRejectionRecord {
+    #[cfg_attr(test, serde(default))]
+    source: String,
+    row: usize,
+    #[serde(rename = "type")]
+    record_type: String,
+    /// Empty, like `tx`, when the row couldn't be read.
+    #[serde(rename = "client")]
+    client_id: Option<ClientId>,
+    #[serde(rename = "tx")]
+    txid: Option<TransactionId>,
+    /// Empty when missing or not a number.
+    #[cfg_attr(
+        test,
+        serde(default, deserialize_with = "crate::deserialize_optional_decimal")
+    )]
+    amount: Option<DecimalType>,
+    reason: String,
+}
+
+impl CsvAckRecord {
+    pub fn new(row: usize, outcome: &str, reason: &str) -> Self {
+        Self {
+            row,
+            outcome: outcome.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl CsvRejectionRecord {
+    pub fn take_source(&mut self) -> String {
+        std::mem::take(&mut self.source)
+    }
 }
 
 #[cfg(test)]
@@ -49,28 +245,109 @@ impl CsvOutputRecord {
     }
 }
 
-fn deserialize_lowercase<'de, D>(deserializer: D) -> Result<String, D::Error>
+pub fn deserialize_lowercase<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
     Ok(String::deserialize(deserializer)?.to_lowercase())
 }
 
-fn serialize_decimal<S>(dec: &DecimalType, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_str(&dec.round_dp(DECIMAL_ACCURACY).to_string())
+/// Why a row was skipped by the parser without ever reaching the engine.
+#[derive(Debug, Clone, Copy)]
+pub enum SkipReason {
+    NegativeAmount,
+    UnknownRecordType,
+    MissingAmount,
+    MissingRecipient,
+    /// Only in strict mode, which holds dispute, resolve and chargeback rows to the brief's amountless form.
+    UnexpectedAmount,
+    /// More significant decimal places than `DECIMAL_ACCURACY`, in strict mode or under `PrecisionPolicy::Reject`.
+    ExcessPrecision,
+    ExtraColumns,
+    MalformedRow,
 }
 
-pub async fn process_input(
-    engine: &mut EngineHandle,
-    input_csv: impl tokio::io::AsyncRead + Unpin + Send,
-    verbose: bool,
-) -> Result<(), Report<AppError>> {
+impl SkipReason {
+    /// Stable machine-readable identifier, used in the rejections report.
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            SkipReason::NegativeAmount => "negative_amount",
+            SkipReason::UnknownRecordType => "unknown_record_type",
+            SkipReason::MissingAmount => "missing_amount",
+            SkipReason::MissingRecipient => "missing_recipient",
+            SkipReason::UnexpectedAmount => "unexpected_amount",
+            SkipReason::ExcessPrecision => "excess_precision",
+            SkipReason::ExtraColumns => "extra_columns",
+            SkipReason::MalformedRow => "malformed_row",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NegativeAmount => write!(f, "Negative amount, assumed invalid"),
+            SkipReason::UnknownRecordType => write!(f, "Unknown record type"),
+            SkipReason::MissingAmount => {
+                write!(f, "Missing amount for a deposit, withdrawal or transfer")
+            }
+            SkipReason::MissingRecipient => write!(f, "Missing receiving client for a transfer"),
+            SkipReason::UnexpectedAmount => {
+                write!(f, "Amount given for a dispute, resolve or chargeback")
+            }
+            SkipReason::ExcessPrecision => {
+                write!(f, "Amount has more than {DECIMAL_ACCURACY} decimal places")
+            }
+            SkipReason::ExtraColumns => write!(f, "More columns than the header"),
+            SkipReason::MalformedRow => write!(f, "Malformed row"),
+        }
+    }
+}
+
+pub enum RowOutcome {
+    /// The row reached the engine, with the engine's outcome.
+    Processed(EventOutcome),
+    Skipped(SkipReason),
+}
+
+/// Outcome of a single input row, reported to ingestors that opt in to acknowledgements.
+pub struct RowAck {
+    /// Where the row came from: an input path, `stdin`, or a connection's peer address.
+    pub source: Arc<str>,
+    /// Zero-based index of the data row, excluding the header.
+    pub row_index: usize,
+    /// `None` when the row couldn't be read as a record.
+    pub record: Option<InputRecord>,
+    pub outcome: RowOutcome,
+}
+
+impl RowAck {
+    /// The stable reason code if the row was not applied.
+    fn rejection_reason_code(&self) -> Option<&'static str> {
+        match &self.outcome {
+            RowOutcome::Processed(EventOutcome::Applied) => None,
+            RowOutcome::Processed(EventOutcome::Rejected(report)) => {
+                Some(report.current_context().reason_code())
+            }
+            RowOutcome::Skipped(skip_reason) => Some(skip_reason.reason_code()),
+        }
+    }
+}
+
+/// Rows read from any input format, ending at the end of input or after an error reading it.
+pub type InputRows<'a> = BoxStream<'a, Result<InputRow, Report<AppError>>>;
+
+/// Read the csv rows of `input` after its header.
+/// Rows with more columns than the header are read as far as the header goes and flagged,
+/// and any row that can't be read is `Malformed` rather than ending the stream.
+pub async fn read_rows<'a>(
+    input_csv: impl tokio::io::AsyncRead + Unpin + Send + 'a,
+) -> Result<InputRows<'a>, Report<AppError>> {
     let mut reader = csv_async::AsyncReaderBuilder::new()
         .trim(csv_async::Trim::All)
-        .create_deserializer(input_csv);
+        // Column counts are checked for each row instead:
+        .flexible(true)
+        .create_reader(input_csv);
 
     let normalised_headers = reader
         .headers()
@@ -79,34 +356,177 @@ pub async fn process_input(
         .iter()
         .map(|h| h.to_lowercase())
         .collect::<Vec<_>>();
-    reader.set_headers(csv_async::StringRecord::from(normalised_headers));
+    let headers = csv_async::StringRecord::from(normalised_headers);
 
-    let mut records = reader.deserialize::<CsvInputRecord>();
+    let rows = reader
+        .into_records()
+        .map(move |row_result| match row_result {
+            Err(error) if error.is_io_error() => Err(Report::new(error).change_context(AppError)),
+            Err(error) => Ok(InputRow::Malformed(error.to_string())),
+            Ok(mut row) => {
+                let extra_columns = row.len() > headers.len();
+                row.truncate(headers.len());
+                Ok(match row.deserialize::<InputRecord>(Some(&headers)) {
+                    Ok(record) => InputRow::Record {
+                        record,
+                        extra_columns,
+                    },
+                    Err(error) => InputRow::Malformed(error.to_string()),
+                })
+            }
+        });
+    Ok(rows.boxed())
+}
 
+/// Stream `rows` into the engine, naming `source` in acks and errors, checking each row as it arrives.
+/// When `ack_tx` is provided, each row's event waits for the engine's outcome which is then sent to `ack_tx`,
+/// rows skipped by the parser (e.g. negative amounts) are acknowledged too.
+pub async fn process_rows(
+    engine: &EngineSender,
+    mut rows: InputRows<'_>,
+    source: &str,
+    options: InputOptions,
+    verbose: bool,
+    ack_tx: Option<tokio::sync::mpsc::Sender<RowAck>>,
+) -> Result<(), Report<AppError>> {
+    let source = Arc::from(source);
     let mut row_index: usize = 0;
-    while let Some(row_result) = records.next().await {
-        process_csv_row(engine, row_result, verbose)
-            .await
-            .attach_with(|| format!("Processing CSV row at index {}", row_index))?;
+    while let Some(row) = rows.next().await {
+        let row = row.attach_with(|| format!("Reading row at index {row_index} of {source}"))?;
+        process_row(
+            engine,
+            row,
+            &source,
+            row_index,
+            options,
+            verbose,
+            ack_tx.as_ref(),
+        )
+        .await
+        .attach_with(|| format!("Processing row at index {row_index} of {source}"))?;
         row_index += 1;
     }
 
     Ok(())
 }
 
+/// Read all of `rows`, failing on the first that strict validation doesn't allow, so they can be processed
+/// knowing none will fail. Holds every row in memory.
+pub async fn validate_rows(
+    mut rows: InputRows<'_>,
+    source: &str,
+    precision: PrecisionPolicy,
+) -> Result<Vec<InputRow>, Report<AppError>> {
+    let mut valid_rows = Vec::new();
+    while let Some(row) = rows.next().await {
+        let row_index = valid_rows.len();
+        let row = row.attach_with(|| format!("Reading row at index {row_index} of {source}"))?;
+        check_strict(&row, precision)
+            .attach_with(|| format!("Validating row at index {row_index} of {source}"))?;
+        valid_rows.push(row);
+    }
+    Ok(valid_rows)
+}
+
+/// Write the selected clients in the requested order.
+/// With `lock_reasons`, the `locked` column holds why each client is locked instead of a bool.
 pub async fn output_client_state(
     all_clients_state: &AllClientsState,
     writer: impl AsyncWrite + Unpin,
+    options: &OutputOptions,
 ) -> Result<(), Report<AppError>> {
     let mut wtr = csv_async::AsyncSerializer::from_writer(writer);
 
-    for (client_id, client) in all_clients_state.iter() {
+    let show_currency = output::has_named_currencies(all_clients_state);
+    let show_fees = output::has_fees(all_clients_state);
+    for row in output::select_rows(all_clients_state, options) {
+        let client = row.client;
         wtr.serialize(&CsvOutputRecord {
-            client_id: *client_id,
-            available: client.available(),
-            held: client.held(),
-            total: client.total(),
-            locked: client.locked(),
+            client_id: row.client_id,
+            currency: show_currency.then(|| row.currency.clone()),
+            available: row.accounts.available,
+            held: row.accounts.held,
+            total: row.accounts.total(),
+            locked: if options.lock_reasons {
+                LockedColumn::Reason(
+                    client
+                        .lock_reason()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                )
+            } else {
+                LockedColumn::Locked(client.locked())
+            },
+            fees: show_fees.then(|| client.fees_paid(row.currency)),
+        })
+        .await
+        .change_context(AppError)?;
+    }
+
+    wtr.flush().await.change_context(AppError)?;
+
+    Ok(())
+}
+
+/// Write each acknowledgement as a csv row as soon as it arrives, until all ack senders are dropped.
+pub async fn output_acks(
+    mut ack_rx: tokio::sync::mpsc::Receiver<RowAck>,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Report<AppError>> {
+    let mut wtr = csv_async::AsyncSerializer::from_writer(writer);
+
+    while let Some(ack) = ack_rx.recv().await {
+        let (outcome, reason) = match ack.outcome {
+            RowOutcome::Processed(EventOutcome::Applied) => ("applied", String::new()),
+            RowOutcome::Processed(EventOutcome::Rejected(report)) => {
+                ("rejected", report.current_context().to_string())
+            }
+            RowOutcome::Skipped(skip_reason) => ("skipped", skip_reason.to_string()),
+        };
+        wtr.serialize(&CsvAckRecord {
+            row: ack.row_index,
+            outcome: outcome.to_string(),
+            reason,
+        })
+        .await
+        .change_context(AppError)?;
+        // Submitters are likely waiting on each ack, don't sit on them in the buffer:
+        wtr.flush().await.change_context(AppError)?;
+    }
+
+    Ok(())
+}
+
+/// Write every row that was not applied, whether rejected by the engine or skipped by the parser,
+/// until all ack senders are dropped.
+pub async fn output_rejections(
+    mut ack_rx: tokio::sync::mpsc::Receiver<RowAck>,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Report<AppError>> {
+    let mut wtr = csv_async::AsyncSerializer::from_writer(writer);
+
+    while let Some(ack) = ack_rx.recv().await {
+        let Some(reason) = ack.rejection_reason_code() else {
+            continue;
+        };
+        let (record_type, client_id, txid, amount) = match ack.record {
+            Some(record) => (
+                record.record_type,
+                Some(record.client_id),
+                Some(record.txid),
+                // As written, rather than as the precision policy would have read it:
+                record.amount.and_then(|amount| parse_amount(&amount).ok()),
+            ),
+            None => Default::default(),
+        };
+        wtr.serialize(&CsvRejectionRecord {
+            source: ack.source.to_string(),
+            row: ack.row_index,
+            record_type,
+            client_id,
+            txid,
+            amount,
+            reason: reason.to_string(),
         })
         .await
         .change_context(AppError)?;
@@ -117,84 +537,246 @@ pub async fn output_client_state(
     Ok(())
 }
 
-async fn process_csv_row(
-    engine: &mut EngineHandle,
-    row_result: Result<CsvInputRecord, csv_async::Error>,
+/// Send a single row to the engine, whatever format it was read from,
+/// acknowledging it to `ack_tx` if given.
+async fn process_row(
+    engine: &EngineSender,
+    row: InputRow,
+    source: &Arc<str>,
+    row_index: usize,
+    options: InputOptions,
     verbose: bool,
+    ack_tx: Option<&tokio::sync::mpsc::Sender<RowAck>>,
 ) -> Result<(), Report<AppError>> {
-    let row_record = row_result.change_context(AppError)?;
+    if options.validation == RowValidation::Strict {
+        check_strict(&row, options.precision)?;
+    }
+    let event = match row_to_event(&row, options)? {
+        Ok(event) => event,
+        Err(skip_reason) => {
+            if verbose {
+                eprintln!(
+                    "Warning: skipping record ({skip_reason}) at row {row_index} of {source}: {row}"
+                );
+            }
+            if let Some(ack_tx) = ack_tx {
+                send_ack(
+                    ack_tx,
+                    source,
+                    row_index,
+                    row.into_record(),
+                    RowOutcome::Skipped(skip_reason),
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+    };
+
+    // Will block until event is accepted by channel, providing backpressure to the csv reading:
+    if let Some(ack_tx) = ack_tx {
+        // Waiting on each reply serialises this ingestor with the engine, but keeps acks in row order:
+        let outcome = engine
+            .send_event_with_reply(event)
+            .await?
+            .await
+            .change_context(AppError)
+            .attach("engine shutdown unexpectedly")?;
+        send_ack(
+            ack_tx,
+            source,
+            row_index,
+            row.into_record(),
+            RowOutcome::Processed(outcome),
+        )
+        .await?;
+    } else {
+        engine.send_event(event).await?;
+    }
 
-    match row_record.record_type.as_str() {
-        RECORD_TYPE_DEPOSIT | RECORD_TYPE_WITHDRAWAL => {
-            let amount = row_record
-                .amount
-                .ok_or_else(|| Report::new(AppError).attach("Missing amount column in CSV"))?;
+    Ok(())
+}
+
+/// Errors on any row strict validation doesn't allow.
+fn check_strict(row: &InputRow, precision: PrecisionPolicy) -> Result<(), Report<AppError>> {
+    let options = InputOptions {
+        validation: RowValidation::Strict,
+        precision,
+    };
+    match row_to_event(row, options)? {
+        Ok(_) => Ok(()),
+        Err(skip_reason) => Err(Report::new(AppError).attach(format!("{skip_reason}: {row}"))),
+    }
+}
+
+/// Convert a row into its engine event, or the reason the row should be skipped.
+/// Errors on rows that are malformed beyond skipping under `options.validation`.
+fn row_to_event(
+    row: &InputRow,
+    options: InputOptions,
+) -> Result<Result<EngineEvent, SkipReason>, Report<AppError>> {
+    // The default mode aborts on these, as it always has:
+    let malformed = |skip_reason: SkipReason, error: &str| match options.validation {
+        RowValidation::Default => Err(Report::new(AppError).attach(error.to_string())),
+        RowValidation::Strict | RowValidation::Lenient => Ok(Err(skip_reason)),
+    };
+    let strict = options.validation == RowValidation::Strict;
+
+    let row_record = match row {
+        InputRow::Record {
+            extra_columns: true,
+            ..
+        } => {
+            return malformed(
+                SkipReason::ExtraColumns,
+                "Row has more columns than `type, client, tx, amount, currency, to, timestamp`",
+            );
+        }
+        InputRow::Record { record, .. } => record,
+        InputRow::Malformed(error) => return malformed(SkipReason::MalformedRow, error),
+    };
+    let amount = match row_record
+        .amount
+        .as_deref()
+        .map(|amount| options.precision.parse(amount))
+        .transpose()
+    {
+        Ok(amount) => amount,
+        Err(error) => return malformed(SkipReason::MalformedRow, &error),
+    };
+    let reject_excess_precision = (strict || options.precision == PrecisionPolicy::Reject)
+        && row_record
+            .amount
+            .as_deref()
+            .is_some_and(has_excess_precision);
+
+    // Left for the engine to give the time of ingestion when missing:
+    let timestamp = row_record.timestamp;
+    let event = match row_record.record_type.as_str() {
+        RECORD_TYPE_DEPOSIT | RECORD_TYPE_WITHDRAWAL | RECORD_TYPE_TRANSFER => {
+            let Some(amount) = amount else {
+                return malformed(
+                    SkipReason::MissingAmount,
+                    "Missing amount for a deposit, withdrawal or transfer",
+                );
+            };
 
             // Reject/ignore negative amounts:
             if amount < DecimalType::ZERO {
-                if verbose {
-                    eprintln!(
-                        "Warning: skipping record with negative amount, assumed invalid: type={}, client_id={}, txid={}, amount={}",
-                        row_record.record_type, row_record.client_id, row_record.txid, amount
-                    );
-                }
-                return Ok(());
+                return Ok(Err(SkipReason::NegativeAmount));
+            }
+            if reject_excess_precision {
+                return Ok(Err(SkipReason::ExcessPrecision));
             }
 
+            let currency = row_record
+                .currency
+                .as_deref()
+                .map(Currency::new)
+                .unwrap_or_default();
             match row_record.record_type.as_str() {
-                RECORD_TYPE_DEPOSIT => {
-                    // Will block until event is accepted by channel, providing backpressure to the csv reading:
-                    engine
-                        .send_event(EngineEvent::Deposit {
-                            txid: row_record.txid,
-                            client_id: row_record.client_id,
-                            amount,
-                        })
-                        .await?;
-                }
-                RECORD_TYPE_WITHDRAWAL => {
-                    // Will block until event is accepted by channel, providing backpressure to the csv reading:
-                    engine
-                        .send_event(EngineEvent::Withdrawal {
-                            txid: row_record.txid,
-                            client_id: row_record.client_id,
-                            amount,
-                        })
-                        .await?;
-                }
-                _ => unreachable!(),
-            }
-        }
-        RECORD_TYPE_DISPUTE => {
-            engine
-                .send_event(EngineEvent::Dispute {
+                RECORD_TYPE_DEPOSIT => EngineEvent::Deposit {
                     txid: row_record.txid,
                     client_id: row_record.client_id,
-                })
-                .await?;
-        }
-        RECORD_TYPE_RESOLVE => {
-            engine
-                .send_event(EngineEvent::Resolve {
-                    txid: row_record.txid,
-                    client_id: row_record.client_id,
-                })
-                .await?;
-        }
-        RECORD_TYPE_CHARGEBACK => {
-            engine
-                .send_event(EngineEvent::Chargeback {
+                    amount,
+                    currency,
+                    timestamp,
+                },
+                RECORD_TYPE_WITHDRAWAL => EngineEvent::Withdrawal {
                     txid: row_record.txid,
                     client_id: row_record.client_id,
-                })
-                .await?;
+                    amount,
+                    currency,
+                    timestamp,
+                },
+                RECORD_TYPE_TRANSFER => {
+                    let Some(to) = row_record.to else {
+                        return malformed(
+                            SkipReason::MissingRecipient,
+                            "Missing receiving client for a transfer",
+                        );
+                    };
+                    EngineEvent::Transfer {
+                        txid: row_record.txid,
+                        from: row_record.client_id,
+                        to,
+                        amount,
+                        currency,
+                        timestamp,
+                    }
+                }
+                _ => unreachable!(),
+            }
         }
-        other_type => {
-            if verbose {
-                eprintln!("Warning: skipping unknown record type '{other_type}'")
+        RECORD_TYPE_DISPUTE | RECORD_TYPE_RESOLVE | RECORD_TYPE_CHARGEBACK => {
+            if strict && amount.is_some() {
+                return Ok(Err(SkipReason::UnexpectedAmount));
+            }
+            // Optional, to act on only part of the transaction:
+            if amount.is_some_and(|amount| amount < DecimalType::ZERO) {
+                return Ok(Err(SkipReason::NegativeAmount));
+            }
+            if reject_excess_precision {
+                return Ok(Err(SkipReason::ExcessPrecision));
+            }
+
+            let (txid, client_id) = (row_record.txid, row_record.client_id);
+            match row_record.record_type.as_str() {
+                RECORD_TYPE_DISPUTE => EngineEvent::Dispute {
+                    txid,
+                    client_id,
+                    amount,
+                    timestamp,
+                },
+                RECORD_TYPE_RESOLVE => EngineEvent::Resolve {
+                    txid,
+                    client_id,
+                    amount,
+                    timestamp,
+                },
+                RECORD_TYPE_CHARGEBACK => EngineEvent::Chargeback {
+                    txid,
+                    client_id,
+                    amount,
+                    timestamp,
+                },
+                _ => unreachable!(),
             }
         }
-    }
+        _ => return Ok(Err(SkipReason::UnknownRecordType)),
+    };
+    Ok(Ok(event))
+}
 
-    Ok(())
+fn parse_amount(amount: &str) -> Result<DecimalType, String> {
+    amount
+        .trim()
+        .parse()
+        .map_err(|error| format!("Invalid amount '{amount}': {error}"))
+}
+
+/// Whether `amount` has significant digits past `DECIMAL_ACCURACY` places, trailing zeros don't count.
+fn has_excess_precision(amount: &str) -> bool {
+    amount.trim().split_once('.').is_some_and(|(_, fraction)| {
+        fraction.trim_end_matches('0').len() > DECIMAL_ACCURACY as usize
+    })
+}
+
+async fn send_ack(
+    ack_tx: &tokio::sync::mpsc::Sender<RowAck>,
+    source: &Arc<str>,
+    row_index: usize,
+    record: Option<InputRecord>,
+    outcome: RowOutcome,
+) -> Result<(), Report<AppError>> {
+    ack_tx
+        .send(RowAck {
+            source: source.clone(),
+            row_index,
+            record,
+            outcome,
+        })
+        .await
+        .change_context(AppError)
+        .attach("acknowledgement receiver dropped")
 }
@@ -1,82 +1,439 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 
 use error_stack::{Report, ResultExt};
 
 use crate::{
     DecimalType,
     app_error::AppError,
-    client::{AllClientsState, ClientId},
+    client::{
+        AccountPolicy, AdminAction, AdminAudit, AllClientsState, ClientId, ClientState,
+        DisputeStep, HistoryKind,
+    },
     engine_error::EngineError,
-    transaction::{Transaction, TransactionId, TransactionKind},
+    event_log::EventLog,
+    ledger::{ClientAccounts, Currency, JournalEntry, Ledger, Posting},
+    serialize_decimal, snapshot,
+    transaction::{Timestamp, Transaction, TransactionId, TransactionKind, TransactionState},
 };
 
 const CHANNEL_BUFFER_SIZE: usize = 10_000;
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum EngineEvent {
     Deposit {
         txid: TransactionId,
         client_id: ClientId,
         amount: DecimalType,
+        #[serde(default)]
+        currency: Currency,
+        /// When the event happened, the time it reached the engine if the input row didn't say.
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
     },
     Withdrawal {
         txid: TransactionId,
         client_id: ClientId,
         amount: DecimalType,
+        #[serde(default)]
+        currency: Currency,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    /// Move `amount` from one client to another as a unit, under both clients' lock and balance rules.
+    Transfer {
+        txid: TransactionId,
+        from: ClientId,
+        to: ClientId,
+        amount: DecimalType,
+        #[serde(default)]
+        currency: Currency,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
     },
+    /// Disputes and their resolves and chargebacks act on the asset of the original transaction.
+    /// A transfer is disputed by its receiving client, and each step is mirrored onto its sending side.
     Dispute {
         txid: TransactionId,
         client_id: ClientId,
+        /// Only this part of the transaction, rather than all of it.
+        amount: Option<DecimalType>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
     },
     Resolve {
         txid: TransactionId,
         client_id: ClientId,
+        /// Only this part of the transaction, rather than all of it.
+        amount: Option<DecimalType>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
     },
     Chargeback {
         txid: TransactionId,
         client_id: ClientId,
+        /// Only this part of the transaction, rather than all of it.
+        amount: Option<DecimalType>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    /// Stamped with the engine clock when it reaches the engine, which a lock it applies is dated by.
+    Admin {
+        client_id: ClientId,
+        action: AdminAction,
+        audit: AdminAudit,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    /// One client's side of a transfer between clients on different shards, only sent by the sharded router.
+    TransferHalf {
+        client_id: ClientId,
+        half: TransferHalf,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    /// Bring the engine's clock up to `timestamp`, expiring disputes as of then. Only sent by the sharded router
+    /// before shutting its shards down, so each shard's final disputes expire as a single engine's would.
+    Tick {
+        timestamp: Timestamp,
     },
     Exit,
 }
 
+/// A side of a transfer, or a dispute step mirrored onto the sending side, for the shard owning `client_id`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TransferHalf {
+    /// Whether the receiving side would be applied, checked on the receiver's shard once its clock has moved on
+    /// and its expired disputes are resolved, as the single engine would before a transfer. Changes no balance.
+    CheckReceive {
+        txid: TransactionId,
+        from: ClientId,
+        amount: DecimalType,
+        currency: Currency,
+    },
+    Send {
+        txid: TransactionId,
+        to: ClientId,
+        amount: DecimalType,
+        currency: Currency,
+    },
+    /// Only sent once the receiving side was checked and the sending side applied, so can't fail.
+    Receive {
+        txid: TransactionId,
+        from: ClientId,
+        amount: DecimalType,
+        currency: Currency,
+    },
+    /// Whether the step can be mirrored onto the sending side, checked before it is applied to the receiving side.
+    /// Changes nothing, see `ClientState::check_can_mirror`.
+    CheckStep {
+        txid: TransactionId,
+        step: DisputeStep,
+    },
+    /// Only sent once the step was applied to the receiving side, see `ClientState::mirror_transfer_step`.
+    Step {
+        txid: TransactionId,
+        step: DisputeStep,
+        amount: Option<DecimalType>,
+    },
+}
+
+impl EngineEvent {
+    /// The client the event acts on, `None` for control events.
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self {
+            EngineEvent::Deposit { client_id, .. }
+            | EngineEvent::Withdrawal { client_id, .. }
+            | EngineEvent::Dispute { client_id, .. }
+            | EngineEvent::Resolve { client_id, .. }
+            | EngineEvent::Chargeback { client_id, .. }
+            | EngineEvent::Admin { client_id, .. }
+            | EngineEvent::TransferHalf { client_id, .. } => Some(*client_id),
+            // The sending client, whose funds are checked first:
+            EngineEvent::Transfer { from, .. } => Some(*from),
+            EngineEvent::Tick { .. } | EngineEvent::Exit => None,
+        }
+    }
+
+    /// The txid of the transaction the event would create, which must be globally unique.
+    pub fn new_txid(&self) -> Option<TransactionId> {
+        match self {
+            EngineEvent::Deposit { txid, .. }
+            | EngineEvent::Withdrawal { txid, .. }
+            | EngineEvent::Transfer { txid, .. } => Some(*txid),
+            // Halves belong to a transfer whose txid the router already checked:
+            EngineEvent::Dispute { .. }
+            | EngineEvent::Resolve { .. }
+            | EngineEvent::Chargeback { .. }
+            | EngineEvent::Admin { .. }
+            | EngineEvent::TransferHalf { .. }
+            | EngineEvent::Tick { .. }
+            | EngineEvent::Exit => None,
+        }
+    }
+
+    /// When the event happened, `None` for exit events, which aren't timed.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            EngineEvent::Deposit { timestamp, .. }
+            | EngineEvent::Withdrawal { timestamp, .. }
+            | EngineEvent::Transfer { timestamp, .. }
+            | EngineEvent::Dispute { timestamp, .. }
+            | EngineEvent::Resolve { timestamp, .. }
+            | EngineEvent::Chargeback { timestamp, .. }
+            | EngineEvent::Admin { timestamp, .. }
+            | EngineEvent::TransferHalf { timestamp, .. } => *timestamp,
+            EngineEvent::Tick { timestamp } => Some(*timestamp),
+            EngineEvent::Exit => None,
+        }
+    }
+
+    /// Give a timed event without a time `clock`, the latest time the engine has seen, so an untimed row among
+    /// historical ones happens alongside them rather than today. A time the event already has is kept as given,
+    /// for `advance_clock` to judge.
+    pub fn stamp(&mut self, clock: Timestamp) {
+        if let Some(timestamp @ None) = self.timestamp_mut() {
+            *timestamp = Some(clock);
+        }
+    }
+
+    /// Move `clock` on to the event's time, or reject an event timed before it rather than move the event and
+    /// silently shift its dispute deadlines.
+    pub fn advance_clock(&self, clock: &mut Timestamp) -> Result<(), Report<EngineError>> {
+        match self.timestamp() {
+            Some(timestamp) if timestamp < *clock => {
+                Err(Report::from(EngineError::TimestampOutOfOrder {
+                    timestamp,
+                    clock: *clock,
+                }))
+            }
+            Some(timestamp) => {
+                *clock = timestamp;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this resumed input event is `logged`, which was stamped with its time if the input had none.
+    pub fn resumes(&self, logged: &EngineEvent) -> bool {
+        let mut event = self.clone();
+        if let Some(timestamp @ None) = event.timestamp_mut() {
+            *timestamp = logged.timestamp();
+        }
+        event == *logged
+    }
+
+    fn timestamp_mut(&mut self) -> Option<&mut Option<Timestamp>> {
+        match self {
+            EngineEvent::Deposit { timestamp, .. }
+            | EngineEvent::Withdrawal { timestamp, .. }
+            | EngineEvent::Transfer { timestamp, .. }
+            | EngineEvent::Dispute { timestamp, .. }
+            | EngineEvent::Resolve { timestamp, .. }
+            | EngineEvent::Chargeback { timestamp, .. }
+            | EngineEvent::Admin { timestamp, .. }
+            | EngineEvent::TransferHalf { timestamp, .. } => Some(timestamp),
+            EngineEvent::Tick { .. } | EngineEvent::Exit => None,
+        }
+    }
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct EngineState {
     all_clients_state: AllClientsState,
     // To avoid re-processing txids
     seen_txids: HashSet<TransactionId>,
+    /// Events handled over the state's lifetime, whether applied or rejected, carried across snapshots.
+    events_processed: u64,
+    ledger: Ledger,
+    /// The latest time of any event handled, which disputes expire by.
+    #[serde(default)]
+    clock: Timestamp,
 }
 
 impl EngineState {
     pub fn all_clients_state(&self) -> &AllClientsState {
         &self.all_clients_state
     }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Combine the states of shards that each owned a disjoint set of clients.
+    /// `seen_txids` is the global set the router checked against, so is a superset of the shards' sets,
+    /// and `router_events` the events the router rejected itself without reaching a shard.
+    pub fn merge_shards(
+        shard_states: Vec<EngineState>,
+        seen_txids: HashSet<TransactionId>,
+        router_events: u64,
+    ) -> Result<Self, Report<EngineError>> {
+        let mut merged = EngineState {
+            all_clients_state: AllClientsState::default(),
+            seen_txids,
+            events_processed: router_events,
+            ledger: Ledger::default(),
+            clock: 0,
+        };
+        for shard_state in shard_states {
+            merged
+                .all_clients_state
+                .extend(shard_state.all_clients_state);
+            merged.events_processed += shard_state.events_processed;
+            merged.clock = merged.clock.max(shard_state.clock);
+            merged.ledger.merge(shard_state.ledger)?;
+        }
+        Ok(merged)
+    }
 }
 
-enum EngineResponse {
+pub enum EngineResponse {
     EngineState(EngineState),
+    /// `None` if the client doesn't exist.
+    ClientBalance(Option<ClientBalance>),
+    /// A balance per client per asset, ordered by client id then currency.
+    AllClients(Vec<ClientBalance>),
+    /// `None` if the client or transaction doesn't exist.
+    Transaction(Option<TransactionSummary>),
 }
 
-pub struct EngineHandle {
-    engine_event_tx: tokio::sync::mpsc::Sender<EngineEvent>,
-    response_rx: tokio::sync::mpsc::Receiver<EngineResponse>,
+/// Read-only questions about the live engine state, answered without affecting it.
+#[derive(Debug, Clone)]
+pub enum EngineQuery {
+    /// The client's balance in one asset, zero if it has never transacted in it.
+    ClientBalance {
+        client_id: ClientId,
+        currency: Currency,
+    },
+    AllClients,
+    Transaction {
+        client_id: ClientId,
+        txid: TransactionId,
+    },
 }
 
-impl EngineHandle {
+#[derive(Debug, serde::Serialize)]
+pub struct ClientBalance {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(skip_serializing_if = "Currency::is_default")]
+    pub currency: Currency,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub available: DecimalType,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub held: DecimalType,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub total: DecimalType,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TransactionSummary {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx")]
+    pub txid: TransactionId,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub amount: DecimalType,
+    /// The part of `amount` currently held under dispute.
+    #[serde(serialize_with = "serialize_decimal")]
+    pub disputed: DecimalType,
+    pub state: TransactionState,
+}
+
+/// The result of a single event, returned to submitters that asked for a reply.
+#[derive(Debug)]
+pub enum EventOutcome {
+    Applied,
+    /// A soft error, the event was ignored and the engine state is unchanged.
+    Rejected(Report<EngineError>),
+}
+
+/// What travels over the engine channel.
+pub enum EngineMessage {
+    /// An event and optionally where to send its outcome.
+    Event {
+        event: EngineEvent,
+        reply_tx: Option<tokio::sync::oneshot::Sender<EventOutcome>>,
+    },
+    /// Queries are not events: they aren't logged or counted, and are answered even while resuming from a log.
+    Query {
+        query: EngineQuery,
+        response_tx: tokio::sync::oneshot::Sender<EngineResponse>,
+    },
+}
+
+/// Cheaply cloneable sending half of the engine, allowing many ingestors to feed a single engine.
+#[derive(Clone)]
+pub struct EngineSender {
+    engine_event_tx: tokio::sync::mpsc::Sender<EngineMessage>,
+}
+
+impl EngineSender {
     /// Resolves once the event has been successfully pushed to the channel.
     pub async fn send_event(&self, event: EngineEvent) -> Result<(), Report<AppError>> {
+        self.send_message(EngineMessage::Event {
+            event,
+            reply_tx: None,
+        })
+        .await
+    }
+
+    /// Like `send_event`, but the returned receiver resolves with the outcome once the engine has processed the event.
+    /// Soft errors are then reported to the receiver instead of being printed by the engine in verbose mode.
+    pub async fn send_event_with_reply(
+        &self,
+        event: EngineEvent,
+    ) -> Result<tokio::sync::oneshot::Receiver<EventOutcome>, Report<AppError>> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.send_message(EngineMessage::Event {
+            event,
+            reply_tx: Some(reply_tx),
+        })
+        .await?;
+        Ok(reply_rx)
+    }
+
+    /// Ask the running engine about its current state, answered in order with the events sent before it.
+    /// Many senders can query concurrently, each gets its own response.
+    pub async fn query(&self, query: EngineQuery) -> Result<EngineResponse, Report<AppError>> {
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        self.send_message(EngineMessage::Query { query, response_tx })
+            .await?;
+        response_rx
+            .await
+            .change_context(AppError)
+            .attach("engine shutdown unexpectedly")
+    }
+
+    pub async fn send_message(&self, message: EngineMessage) -> Result<(), Report<AppError>> {
         self.engine_event_tx
-            .send(event)
+            .send(message)
             .await
             .attach("engine shutdown unexpectedly")
             .change_context(AppError)?;
         Ok(())
     }
+}
+
+pub struct EngineHandle {
+    sender: EngineSender,
+    response_rx: tokio::sync::mpsc::Receiver<EngineResponse>,
+}
+
+impl EngineHandle {
+    /// A new sender feeding this engine, e.g. one per TCP connection.
+    pub fn sender(&self) -> EngineSender {
+        self.sender.clone()
+    }
 
     /// Sends the shutdown event and waits for the final engine state to be returned.
     pub async fn shutdown(mut self) -> Result<EngineState, Report<AppError>> {
-        self.engine_event_tx
-            .send(EngineEvent::Exit)
-            .await
-            .attach("engine shutdown unexpectedly")
-            .change_context(AppError)?;
+        self.sender.send_event(EngineEvent::Exit).await?;
         match self
             .response_rx
             .recv()
@@ -84,6 +441,10 @@ impl EngineHandle {
             .ok_or_else(|| Report::new(AppError).attach("engine shutdown unexpectedly"))?
         {
             EngineResponse::EngineState(engine_state) => Ok(engine_state),
+            _ => {
+                Err(Report::new(AppError)
+                    .attach("engine responded to shutdown with a query response"))
+            }
         }
     }
 }
@@ -93,104 +454,713 @@ enum EventOutput {
     Exit,
 }
 
-/// Spawn the engine future that will stay alive until the `Engine` is dropped or an `EngineEvent::Exit`
-pub fn spawn_engine(verbose: bool) -> EngineHandle {
-    let (engine_event_tx, mut engine_event_rx) = tokio::sync::mpsc::channel(CHANNEL_BUFFER_SIZE);
+/// Engine configuration, fixed for the lifetime of the engine.
+#[derive(Default)]
+pub struct EngineConfig {
+    /// Print soft errors to stderr, unless the submitter asked for a reply.
+    pub verbose: bool,
+    /// Write-ahead log each event is appended to before being applied. Its existing events are replayed on startup.
+    pub event_log: Option<EventLog>,
+    /// After replaying the event log, expect incoming events to start with the logged events again and skip them,
+    /// replying with their original outcomes. Lets a restarted run be fed the same input from the beginning.
+    pub resume_from_log: bool,
+    /// State to start from instead of empty, e.g. loaded from a snapshot.
+    pub initial_state: Option<EngineState>,
+    /// Where to periodically write snapshots of the state, every `n` processed events.
+    pub snapshot_every: Option<(std::path::PathBuf, std::num::NonZeroU64)>,
+    /// Amount limits and dispute rules applied to every client.
+    pub policy: AccountPolicy,
+    /// Keep every applied event in its client's history, e.g. for statements. Costs memory per event.
+    pub record_history: bool,
+    /// Keep every transfer posted to the ledger in its journal, e.g. for exporting it. Costs memory per event.
+    pub record_journal: bool,
+}
+
+impl EngineConfig {
+    /// The settings every shard of a sharded engine shares.
+    /// Logging, snapshots and initial state are whole-engine concerns, so are not carried over.
+    pub fn shard_config(&self) -> EngineConfig {
+        EngineConfig {
+            verbose: self.verbose,
+            policy: self.policy,
+            record_history: self.record_history,
+            record_journal: self.record_journal,
+            ..Default::default()
+        }
+    }
+}
+
+/// The channels an engine task and its handle talk over.
+pub fn engine_channels() -> (
+    EngineHandle,
+    tokio::sync::mpsc::Receiver<EngineMessage>,
+    tokio::sync::mpsc::Sender<EngineResponse>,
+) {
+    let (engine_event_tx, engine_event_rx) = tokio::sync::mpsc::channel(CHANNEL_BUFFER_SIZE);
     let (response_tx, response_rx) = tokio::sync::mpsc::channel(CHANNEL_BUFFER_SIZE);
+    (
+        EngineHandle {
+            sender: EngineSender { engine_event_tx },
+            response_rx,
+        },
+        engine_event_rx,
+        response_tx,
+    )
+}
+
+/// Spawn the engine future that will stay alive until the `Engine` is dropped or an `EngineEvent::Exit`
+pub fn spawn_engine(mut config: EngineConfig) -> EngineHandle {
+    let (engine_handle, mut engine_event_rx, response_tx) = engine_channels();
     tokio::spawn({
         async move {
-            let mut engine_state = EngineState {
-                all_clients_state: AllClientsState::default(),
-                seen_txids: HashSet::new(),
-            };
-            while let Some(event) = engine_event_rx.recv().await {
-                match handle_engine_event(&mut engine_state, event).await {
-                    Ok(EventOutput::Exit) => {
-                        response_tx
-                            .send(EngineResponse::EngineState(engine_state))
-                            .await
-                            .unwrap();
-                        return;
+            let mut engine_state = config.initial_state.take().unwrap_or_default();
+
+            // Replayed events with their outcomes, still to be matched against the resumed input:
+            let mut resumed = VecDeque::new();
+            if let Some(event_log) = &mut config.event_log {
+                for event in event_log.take_recovered() {
+                    let outcome = match handle_engine_event(
+                        &mut engine_state,
+                        event.clone(),
+                        &config.policy,
+                        config.record_history,
+                        config.record_journal,
+                    )
+                    .await
+                    {
+                        Ok(_) => EventOutcome::Applied,
+                        Err(report) => rejection_or_exit(report),
+                    };
+                    if config.resume_from_log {
+                        resumed.push_back((event, outcome));
                     }
-                    Ok(EventOutput::Continue) => {}
-                    Err(report) => match report.current_context() {
-                        EngineError::InternalError => {
-                            eprintln!("{report:?}");
-                            std::process::exit(1);
+                }
+            }
+
+            // Messages that apply no event leave the count where it is, and mustn't write the same snapshot again:
+            let mut last_snapshot_at = engine_state.events_processed;
+            while let Some(message) = engine_event_rx.recv().await {
+                let (event, reply_tx) = match message {
+                    EngineMessage::Event { event, reply_tx } => (event, reply_tx),
+                    EngineMessage::Query { query, response_tx } => {
+                        // The querier may have stopped waiting, that's their choice:
+                        let _ = response_tx.send(answer_query(&engine_state, query));
+                        continue;
+                    }
+                };
+                let outcome = match resumed.pop_front() {
+                    // Already applied before the restart:
+                    Some((logged_event, logged_outcome)) if event.resumes(&logged_event) => {
+                        logged_outcome
+                    }
+                    Some((logged_event, _)) if event != EngineEvent::Exit => {
+                        eprintln!(
+                            "{:?}",
+                            Report::new(EngineError::InternalError).attach(format!(
+                                "Resumed input diverges from the event log, expected {logged_event:?} but got {event:?}"
+                            ))
+                        );
+                        std::process::exit(1);
+                    }
+                    _ => {
+                        let mut event = event;
+                        // Logged with its time, so a replay expires disputes exactly as this run did:
+                        event.stamp(engine_state.clock);
+                        if event != EngineEvent::Exit {
+                            if let Some(event_log) = &mut config.event_log {
+                                // Can't apply an event that might not survive a restart:
+                                if let Err(report) = event_log.append(&event).await {
+                                    eprintln!("{report:?}");
+                                    std::process::exit(1);
+                                }
+                            }
                         }
-                        soft_error => {
-                            if verbose {
-                                eprintln!("Engine rejected request: {:?}", soft_error);
+                        match handle_engine_event(
+                            &mut engine_state,
+                            event,
+                            &config.policy,
+                            config.record_history,
+                            config.record_journal,
+                        )
+                        .await
+                        {
+                            Ok(EventOutput::Exit) => {
+                                response_tx
+                                    .send(EngineResponse::EngineState(engine_state))
+                                    .await
+                                    .unwrap();
+                                return;
                             }
+                            Ok(EventOutput::Continue) => EventOutcome::Applied,
+                            Err(report) => {
+                                if config.verbose && reply_tx.is_none() {
+                                    eprintln!(
+                                        "Engine rejected request: {:?}",
+                                        report.current_context()
+                                    );
+                                }
+                                rejection_or_exit(report)
+                            }
+                        }
+                    }
+                };
+                if let Some(reply_tx) = reply_tx {
+                    // The submitter may have stopped waiting, that's their choice:
+                    let _ = reply_tx.send(outcome);
+                }
+                if let Some((snapshot_path, every)) = &config.snapshot_every {
+                    if engine_state.events_processed != last_snapshot_at
+                        && engine_state.events_processed % every.get() == 0
+                    {
+                        last_snapshot_at = engine_state.events_processed;
+                        // A missed periodic snapshot loses nothing, the next one or the final state will cover it:
+                        if let Err(report) =
+                            snapshot::write_snapshot(&engine_state, snapshot_path).await
+                        {
+                            eprintln!("Periodic snapshot failed: {report:?}");
                         }
-                    },
+                    }
                 }
             }
         }
     });
-    EngineHandle {
-        engine_event_tx,
-        response_rx,
+    engine_handle
+}
+
+/// Soft errors become a rejection, whereas the hard `EngineError::InternalError` exits the process.
+fn rejection_or_exit(report: Report<EngineError>) -> EventOutcome {
+    if let EngineError::InternalError = report.current_context() {
+        eprintln!("{report:?}");
+        std::process::exit(1);
+    }
+    EventOutcome::Rejected(report)
+}
+
+fn answer_query(engine: &EngineState, query: EngineQuery) -> EngineResponse {
+    match query {
+        EngineQuery::ClientBalance {
+            client_id,
+            currency,
+        } => EngineResponse::ClientBalance(engine.all_clients_state.get(client_id).map(|client| {
+            let accounts = client.accounts(&currency);
+            client_balance(client_id, client, &currency, accounts)
+        })),
+        EngineQuery::AllClients => {
+            let mut balances = engine
+                .all_clients_state
+                .iter()
+                .flat_map(|(client_id, client)| {
+                    client.balances().map(|(currency, accounts)| {
+                        client_balance(*client_id, client, currency, accounts)
+                    })
+                })
+                .collect::<Vec<_>>();
+            // Each client's balances are already in currency order:
+            balances.sort_by_key(|balance| balance.client_id);
+            EngineResponse::AllClients(balances)
+        }
+        EngineQuery::Transaction { client_id, txid } => EngineResponse::Transaction(
+            engine
+                .all_clients_state
+                .get(client_id)
+                .and_then(|client| client.transaction(txid))
+                .map(|tx| TransactionSummary {
+                    client_id,
+                    txid,
+                    kind: tx.kind().name(),
+                    amount: tx.amount(),
+                    disputed: tx.disputed(),
+                    state: tx.state(),
+                }),
+        ),
+    }
+}
+
+fn client_balance(
+    client_id: ClientId,
+    client: &ClientState,
+    currency: &Currency,
+    accounts: ClientAccounts,
+) -> ClientBalance {
+    ClientBalance {
+        client_id,
+        currency: currency.clone(),
+        available: accounts.available,
+        held: accounts.held,
+        total: accounts.total(),
+        locked: client.locked(),
+        lock_reason: client.lock_reason().map(ToString::to_string),
     }
 }
 
 async fn handle_engine_event(
     engine: &mut EngineState,
     event: EngineEvent,
+    policy: &AccountPolicy,
+    record_history: bool,
+    record_journal: bool,
 ) -> Result<EventOutput, Report<EngineError>> {
-    match event {
+    // Halves are counted by the sharded router, as the event they are part of:
+    if !matches!(
+        event,
+        EngineEvent::Exit | EngineEvent::TransferHalf { .. } | EngineEvent::Tick { .. }
+    ) {
+        engine.events_processed += 1;
+    }
+    event.advance_clock(&mut engine.clock)?;
+    let now = engine.clock;
+    // Disputes expire lazily, as of the next event acting on their client, so the same events expire them
+    // the same way however the clients are sharded. The expiry stands even if the event is then rejected:
+    for client_id in clients_swept_by(engine, &event) {
+        expire_disputes(engine, client_id, policy, record_history, record_journal)?;
+    }
+    // Everything the event posted with the client it was posted for, a transfer between clients posting two:
+    let (kind, txid, posted): (_, _, [Option<(ClientId, Posting)>; 2]) = match event {
         EngineEvent::Deposit {
             txid,
             client_id,
             amount,
+            currency,
+            ..
         } => {
             let tx = Transaction::new(
                 &mut engine.seen_txids,
                 txid,
                 TransactionKind::Deposit { amount },
+                currency,
+                now,
             )?;
             let client = engine
                 .all_clients_state
                 .get_unlocked_client_mut_or_create(client_id)?;
-            client.deposit(tx);
+            let posting = client.deposit(tx, policy, &mut engine.ledger)?;
+            (
+                HistoryKind::Deposit,
+                txid,
+                [Some((client_id, posting)), None],
+            )
         }
         EngineEvent::Withdrawal {
             txid,
             client_id,
             amount,
+            currency,
+            ..
         } => {
             let tx = Transaction::new(
                 &mut engine.seen_txids,
                 txid,
                 TransactionKind::Withdrawal { amount },
+                currency,
+                now,
             )?;
             let client = engine
                 .all_clients_state
                 .get_unlocked_client_mut_or_create(client_id)?;
-            client.withdraw(tx)?;
+            // Funds first, so an overdraft isn't reported as breaking a rule:
+            let fee = client.check_can_withdraw(&tx, policy)?;
+            policy.risk.check_withdrawal(client, &tx, fee)?;
+            let posting = client.withdraw(tx, policy, &mut engine.ledger)?;
+            client.record_withdrawal(&policy.risk, txid);
+            (
+                HistoryKind::Withdrawal,
+                txid,
+                [Some((client_id, posting)), None],
+            )
         }
-        EngineEvent::Dispute { txid, client_id } => {
+        EngineEvent::Transfer {
+            txid,
+            from,
+            to,
+            amount,
+            currency,
+            ..
+        } => {
+            let tx = Transaction::new(
+                &mut engine.seen_txids,
+                txid,
+                TransactionKind::TransferOut { amount, to },
+                currency,
+                now,
+            )?;
+            if from == to {
+                return Err(Report::from(EngineError::TransferToSelf(txid)));
+            }
+            let received = Transaction::transfer_in(txid, from, amount, tx.currency().clone(), now);
             engine
+                .all_clients_state
+                .check_can_receive(to, &received, policy)?;
+            let sent = engine
+                .all_clients_state
+                .get_unlocked_client_mut(from)?
+                .ok_or(EngineError::ClientNotFound(from))?
+                .withdraw(tx, policy, &mut engine.ledger)?;
+            let received = engine
+                .all_clients_state
+                .get_unlocked_client_mut_or_create(to)
+                .and_then(|client| client.deposit(received, policy, &mut engine.ledger))
+                .map_err(|report| {
+                    report
+                        .change_context(EngineError::InternalError)
+                        .attach("Receiving side of a transfer failed after being checked")
+                })?;
+            (
+                HistoryKind::Transfer,
+                txid,
+                [Some((from, sent)), Some((to, received))],
+            )
+        }
+        EngineEvent::Dispute {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => {
+            let posting = engine
                 .all_clients_state
                 .get_unlocked_client_mut(client_id)?
                 .ok_or(EngineError::ClientNotFound(client_id))?
-                .dispute_transaction(txid)?;
+                .dispute_transaction(txid, amount, now, policy, &mut engine.ledger)?
+                .into();
+            let mirrored = mirror_transfer_step(
+                engine,
+                client_id,
+                txid,
+                DisputeStep::Dispute,
+                amount,
+                policy,
+            )?;
+            (
+                HistoryKind::Dispute,
+                txid,
+                [Some((client_id, posting)), mirrored],
+            )
         }
-        EngineEvent::Resolve { txid, client_id } => {
-            engine
+        EngineEvent::Resolve {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => {
+            let posting = engine
                 .all_clients_state
                 .get_unlocked_client_mut(client_id)?
                 .ok_or(EngineError::ClientNotFound(client_id))?
-                .resolve_transaction(txid)?;
+                .resolve_transaction(txid, amount, policy, &mut engine.ledger)?
+                .into();
+            let mirrored = mirror_transfer_step(
+                engine,
+                client_id,
+                txid,
+                DisputeStep::Resolve,
+                amount,
+                policy,
+            )?;
+            (
+                HistoryKind::Resolve,
+                txid,
+                [Some((client_id, posting)), mirrored],
+            )
         }
-        EngineEvent::Chargeback { txid, client_id } => {
-            engine
+        EngineEvent::Chargeback {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => {
+            check_can_mirror(engine, client_id, txid, DisputeStep::Chargeback)?;
+            let posting = engine
                 .all_clients_state
                 .get_unlocked_client_mut(client_id)?
                 .ok_or(EngineError::ClientNotFound(client_id))?
-                .chargeback_transaction(txid)?;
+                .chargeback_transaction(txid, amount, now, policy, &mut engine.ledger)?;
+            let mirrored = mirror_transfer_step(
+                engine,
+                client_id,
+                txid,
+                DisputeStep::Chargeback,
+                amount,
+                policy,
+            )?;
+            (
+                HistoryKind::Chargeback,
+                txid,
+                [Some((client_id, posting)), mirrored],
+            )
+        }
+        EngineEvent::TransferHalf {
+            client_id, half, ..
+        } => match half {
+            TransferHalf::CheckReceive {
+                txid,
+                from,
+                amount,
+                currency,
+            } => {
+                let received = Transaction::transfer_in(txid, from, amount, currency, now);
+                engine
+                    .all_clients_state
+                    .check_can_receive(client_id, &received, policy)?;
+                return Ok(EventOutput::Continue);
+            }
+            TransferHalf::Send {
+                txid,
+                to,
+                amount,
+                currency,
+            } => {
+                let tx = Transaction::new(
+                    &mut engine.seen_txids,
+                    txid,
+                    TransactionKind::TransferOut { amount, to },
+                    currency,
+                    now,
+                )?;
+                let posting = engine
+                    .all_clients_state
+                    .get_unlocked_client_mut(client_id)?
+                    .ok_or(EngineError::ClientNotFound(client_id))?
+                    .withdraw(tx, policy, &mut engine.ledger)?;
+                (
+                    HistoryKind::Transfer,
+                    txid,
+                    [Some((client_id, posting)), None],
+                )
+            }
+            TransferHalf::Receive {
+                txid,
+                from,
+                amount,
+                currency,
+            } => {
+                let tx = Transaction::new(
+                    &mut engine.seen_txids,
+                    txid,
+                    TransactionKind::TransferIn { amount, from },
+                    currency,
+                    now,
+                )?;
+                let posting = engine
+                    .all_clients_state
+                    .get_unlocked_client_mut_or_create(client_id)?
+                    .deposit(tx, policy, &mut engine.ledger)?;
+                (
+                    HistoryKind::Transfer,
+                    txid,
+                    [Some((client_id, posting)), None],
+                )
+            }
+            TransferHalf::CheckStep { step, .. } => {
+                if let Some(sender) = engine.all_clients_state.get(client_id) {
+                    sender.check_can_mirror(client_id, step)?;
+                }
+                return Ok(EventOutput::Continue);
+            }
+            TransferHalf::Step { txid, step, amount } => {
+                let posting = engine
+                    .all_clients_state
+                    .get_mut(client_id)
+                    .ok_or(EngineError::ClientNotFound(client_id))?
+                    .mirror_transfer_step(txid, step, amount, now, policy, &mut engine.ledger)?;
+                (
+                    step.into(),
+                    txid,
+                    [posting.map(|posting| (client_id, posting)), None],
+                )
+            }
+        },
+        EngineEvent::Admin {
+            client_id,
+            action,
+            audit,
+            timestamp,
+        } => {
+            engine
+                .all_clients_state
+                .get_mut(client_id)
+                .ok_or(EngineError::ClientNotFound(client_id))?
+                .apply_admin_action(client_id, action, audit, timestamp.unwrap_or(now))?;
+            return Ok(EventOutput::Continue);
         }
+        EngineEvent::Tick { .. } => return Ok(EventOutput::Continue),
         EngineEvent::Exit => return Ok(EventOutput::Exit),
     };
+    for (client_id, posting) in posted.into_iter().flatten() {
+        record_posting(
+            engine,
+            client_id,
+            kind,
+            txid,
+            posting,
+            record_history,
+            record_journal,
+        );
+    }
     Ok(EventOutput::Continue)
 }
+
+/// The clients whose expired disputes are resolved before `event` is applied.
+fn clients_swept_by(engine: &EngineState, event: &EngineEvent) -> Vec<ClientId> {
+    match event {
+        EngineEvent::Transfer { from, to, .. } => vec![*from, *to],
+        // Also the sender of a transfer, as the router mirroring the step would sweep it on the sender's shard:
+        EngineEvent::Dispute {
+            txid, client_id, ..
+        }
+        | EngineEvent::Resolve {
+            txid, client_id, ..
+        }
+        | EngineEvent::Chargeback {
+            txid, client_id, ..
+        } => {
+            let sender = transfer_sender(engine, *client_id, *txid);
+            std::iter::once(*client_id).chain(sender).collect()
+        }
+        // Admin actions don't move funds, so leave expiry to the client's next transaction:
+        EngineEvent::Admin { .. } => vec![],
+        EngineEvent::Tick { .. } | EngineEvent::Exit => {
+            engine.all_clients_state.iter().map(|(id, _)| *id).collect()
+        }
+        event => event.client_id().into_iter().collect(),
+    }
+}
+
+/// Keep what was posted for `txid` of `client_id` in its history and the journal, as configured.
+fn record_posting(
+    engine: &mut EngineState,
+    client_id: ClientId,
+    kind: HistoryKind,
+    txid: TransactionId,
+    posting: Posting,
+    record_history: bool,
+    record_journal: bool,
+) {
+    if !record_history && !record_journal {
+        return;
+    }
+    let client = engine
+        .all_clients_state
+        .get_mut(client_id)
+        .expect("client was just updated");
+    let currency = client
+        .transaction(txid)
+        .expect("transaction was just applied")
+        .currency()
+        .clone();
+    if record_journal {
+        for transfer in std::iter::once(posting.transfer).chain(posting.fee) {
+            engine.ledger.record(JournalEntry {
+                client_id,
+                kind,
+                txid,
+                currency: currency.clone(),
+                from: transfer.from,
+                to: transfer.to,
+                amount: transfer.amount,
+            });
+        }
+    }
+    if record_history {
+        client.record_history(
+            kind,
+            txid,
+            currency,
+            posting.transfer.amount,
+            posting.fee_charged(),
+        );
+    }
+}
+
+/// Resolve the disputes of `client_id` that have outlived the chargeback deadline by the engine's clock.
+fn expire_disputes(
+    engine: &mut EngineState,
+    client_id: ClientId,
+    policy: &AccountPolicy,
+    record_history: bool,
+    record_journal: bool,
+) -> Result<(), Report<EngineError>> {
+    let Some(client) = engine.all_clients_state.get_mut(client_id) else {
+        return Ok(());
+    };
+    let resolved = client
+        .expire_disputes(engine.clock, policy, &mut engine.ledger)
+        .map_err(|report| {
+            report
+                .change_context(EngineError::InternalError)
+                .attach(format!(
+                    "Expiring the disputes of client {client_id} failed"
+                ))
+        })?;
+    for (txid, posting) in resolved {
+        record_posting(
+            engine,
+            client_id,
+            HistoryKind::Resolve,
+            txid,
+            posting,
+            record_history,
+            record_journal,
+        );
+    }
+    Ok(())
+}
+
+/// The sender of `txid` of `client_id`, if it's the receiving side of a transfer.
+fn transfer_sender(
+    engine: &EngineState,
+    client_id: ClientId,
+    txid: TransactionId,
+) -> Option<ClientId> {
+    engine
+        .all_clients_state
+        .get(client_id)
+        .and_then(|client| client.transaction(txid))
+        .and_then(|tx| match tx.kind() {
+            TransactionKind::TransferIn { from, .. } => Some(*from),
+            _ => None,
+        })
+}
+
+/// Before a dispute step is applied to `txid` of `client_id`, check it can be mirrored if it's a transfer.
+/// A sharded engine may own only the receiving client, in which case its router checks the sender instead.
+fn check_can_mirror(
+    engine: &EngineState,
+    client_id: ClientId,
+    txid: TransactionId,
+    step: DisputeStep,
+) -> Result<(), Report<EngineError>> {
+    let Some(from) = transfer_sender(engine, client_id, txid) else {
+        return Ok(());
+    };
+    match engine.all_clients_state.get(from) {
+        Some(sender) => sender.check_can_mirror(from, step),
+        None => Ok(()),
+    }
+}
+
+/// After a dispute step was applied to `txid` of `client_id`, apply it to the sending side if it's a transfer.
+/// A sharded engine may own only the receiving client, in which case its router mirrors the step instead.
+fn mirror_transfer_step(
+    engine: &mut EngineState,
+    client_id: ClientId,
+    txid: TransactionId,
+    step: DisputeStep,
+    amount: Option<DecimalType>,
+    policy: &AccountPolicy,
+) -> Result<Option<(ClientId, Posting)>, Report<EngineError>> {
+    let Some(from) = transfer_sender(engine, client_id, txid) else {
+        return Ok(None);
+    };
+    let now = engine.clock;
+    let Some(sender) = engine.all_clients_state.get_mut(from) else {
+        return Ok(None);
+    };
+    let posting = sender
+        .mirror_transfer_step(txid, step, amount, now, policy, &mut engine.ledger)
+        .map_err(|report| {
+            report
+                .change_context(EngineError::InternalError)
+                .attach("Sending side of a transfer diverged from its receiving side")
+        })?;
+    Ok(posting.map(|posting| (from, posting)))
+}
@@ -1,6 +1,7 @@
 use crate::{
+    DecimalType,
     client::ClientId,
-    transaction::{TransactionId, TransactionState},
+    transaction::{Timestamp, TransactionId, TransactionState},
 };
 
 /// Errors that can occur within the engine.
@@ -12,6 +13,12 @@ pub enum EngineError {
 
     #[error("Client with ID '{0}' is already locked and can no longer be interacted with")]
     ClientLocked(ClientId),
+    #[error("Client with ID '{0}' is closed and can no longer be interacted with")]
+    ClientClosed(ClientId),
+    #[error("Client with ID '{0}' is not locked")]
+    ClientNotLocked(ClientId),
+    #[error("Client with ID '{0}' still has available or held funds")]
+    ClientHasFunds(ClientId),
     #[error("Client with ID '{0}' not found during an operation that requires an existing client")]
     ClientNotFound(ClientId),
     #[error("Insufficient funds for withdrawal")]
@@ -27,9 +34,79 @@ pub enum EngineError {
     #[error("Transaction with ID '{0}' not found")]
     TxNotFound(TransactionId),
     #[error(
-        "Transaction with ID '{0}' cannot be disputed, only deposit transaction types can be disputed"
+        "Transaction with ID '{0}' cannot be disputed, its transaction type is not disputable under the dispute policy"
     )]
     TxCannotBeDisputed(TransactionId),
+    #[error(
+        "Transaction with ID '{0}' is too old to be disputed, it is outside the dispute window"
+    )]
+    DisputeWindowExpired(TransactionId),
+    #[error("Event timestamp {timestamp} is before {clock}, the latest time already seen")]
+    TimestampOutOfOrder {
+        timestamp: Timestamp,
+        clock: Timestamp,
+    },
     #[error("Transaction with ID '{0}' has already been seen")]
     TxAlreadySeen(TransactionId),
+    #[error("Transaction with ID '{0}' would overflow the client's balance")]
+    BalanceOverflow(TransactionId),
+    #[error("Transaction with ID '{0}' is for more than the maximum transaction amount")]
+    AmountOverLimit(TransactionId),
+    #[error("Transaction with ID '{0}' would take the client's balance over the maximum")]
+    BalanceOverLimit(TransactionId),
+    #[error("Transfer with ID '{0}' is from and to the same client")]
+    TransferToSelf(TransactionId),
+    #[error("Transaction with ID '{0}' is for more than the max_withdrawal risk rule allows")]
+    RiskMaxWithdrawal(TransactionId),
+    #[error(
+        "Transaction with ID '{0}' would take the client's recent withdrawals over the velocity risk rule"
+    )]
+    RiskVelocity(TransactionId),
+    #[error(
+        "Transaction with ID '{0}' is from a client with more open disputes than the max_open_disputes risk rule allows"
+    )]
+    RiskOpenDisputes(TransactionId),
+    #[error(
+        "Transaction with ID '{0}' would leave the client's available funds under the min_reserve risk rule"
+    )]
+    RiskMinReserve(TransactionId),
+    #[error(
+        "Transaction with ID '{txid}' can only have up to {limit} {step}, but {requested} was requested"
+    )]
+    DisputeAmountMismatch {
+        txid: TransactionId,
+        step: &'static str,
+        requested: DecimalType,
+        limit: DecimalType,
+    },
+}
+
+impl EngineError {
+    /// Stable machine-readable identifier for the variant, used in the rejections report.
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            EngineError::InternalError => "internal_error",
+            EngineError::ClientLocked(_) => "client_locked",
+            EngineError::ClientClosed(_) => "client_closed",
+            EngineError::ClientNotLocked(_) => "client_not_locked",
+            EngineError::ClientHasFunds(_) => "client_has_funds",
+            EngineError::ClientNotFound(_) => "client_not_found",
+            EngineError::InsufficientFunds => "insufficient_funds",
+            EngineError::TxNotInState { .. } => "tx_not_in_state",
+            EngineError::TxNotFound(_) => "tx_not_found",
+            EngineError::TxCannotBeDisputed(_) => "tx_cannot_be_disputed",
+            EngineError::DisputeWindowExpired(_) => "dispute_window_expired",
+            EngineError::TimestampOutOfOrder { .. } => "timestamp_out_of_order",
+            EngineError::TxAlreadySeen(_) => "tx_already_seen",
+            EngineError::BalanceOverflow(_) => "balance_overflow",
+            EngineError::AmountOverLimit(_) => "amount_over_limit",
+            EngineError::BalanceOverLimit(_) => "balance_over_limit",
+            EngineError::DisputeAmountMismatch { .. } => "dispute_amount_mismatch",
+            EngineError::TransferToSelf(_) => "transfer_to_self",
+            EngineError::RiskMaxWithdrawal(_) => "risk_max_withdrawal",
+            EngineError::RiskVelocity(_) => "risk_velocity",
+            EngineError::RiskOpenDisputes(_) => "risk_open_disputes",
+            EngineError::RiskMinReserve(_) => "risk_min_reserve",
+        }
+    }
 }
@@ -0,0 +1,124 @@
+use std::path::Path;
+
+use error_stack::{Report, ResultExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{app_error::AppError, engine::EngineEvent, engine_error::EngineError};
+
+/// Bytes before each entry's payload: payload length then payload crc32, both little endian `u32`s.
+const ENTRY_HEADER_LEN: usize = 8;
+
+/// Durable write-ahead log of engine events.
+///
+/// Each entry is length-prefixed and checksummed, and synced to disk before the engine applies the event,
+/// so replaying the log rebuilds the exact engine state at the point the process stopped.
+pub struct EventLog {
+    file: tokio::fs::File,
+    /// Events read from an existing log on open, in order, not yet handed to the engine for replay.
+    recovered: Vec<EngineEvent>,
+}
+
+impl EventLog {
+    /// Open or create the log, reading back any existing entries.
+    ///
+    /// A torn final entry, from dying mid-append, is discarded and truncated away.
+    /// Any other corruption is an error, as silently dropping entries would silently drop events.
+    pub async fn open(path: &Path) -> Result<Self, Report<AppError>> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await
+            .change_context(AppError)
+            .attach_with(|| format!("Opening event log {path:?}"))?;
+
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)
+            .await
+            .change_context(AppError)?;
+
+        let mut recovered = vec![];
+        let mut offset = 0;
+        while offset < contents.len() {
+            match decode_entry(&contents[offset..]) {
+                Some((event, entry_len)) => {
+                    recovered.push(
+                        event
+                            .change_context(AppError)
+                            .attach_with(|| format!("Decoding event log entry at byte {offset}"))?,
+                    );
+                    offset += entry_len;
+                }
+                None if is_torn_tail(&contents[offset..]) => break,
+                None => {
+                    return Err(Report::new(AppError)
+                        .attach(format!("Event log {path:?} is corrupt at byte {offset}")));
+                }
+            }
+        }
+
+        if offset < contents.len() {
+            file.set_len(offset as u64).await.change_context(AppError)?;
+        }
+        file.seek(std::io::SeekFrom::Start(offset as u64))
+            .await
+            .change_context(AppError)?;
+
+        Ok(Self { file, recovered })
+    }
+
+    pub fn take_recovered(&mut self) -> Vec<EngineEvent> {
+        std::mem::take(&mut self.recovered)
+    }
+
+    /// Append the event and sync it to disk, only returning once it is durable.
+    pub async fn append(&mut self, event: &EngineEvent) -> Result<(), Report<EngineError>> {
+        let payload = serde_json::to_vec(event).change_context(EngineError::InternalError)?;
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + payload.len());
+        entry.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        entry.extend_from_slice(&payload);
+
+        self.file
+            .write_all(&entry)
+            .await
+            .change_context(EngineError::InternalError)
+            .attach("Appending to event log")?;
+        self.file
+            .sync_data()
+            .await
+            .change_context(EngineError::InternalError)
+            .attach("Syncing event log")?;
+        Ok(())
+    }
+}
+
+/// Decode the entry at the start of `bytes`, returning it with its total length in bytes.
+/// `None` if the entry is incomplete or fails its checksum.
+fn decode_entry(bytes: &[u8]) -> Option<(Result<EngineEvent, serde_json::Error>, usize)> {
+    let payload_len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+    let payload = bytes.get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + payload_len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some((
+        serde_json::from_slice(payload),
+        ENTRY_HEADER_LEN + payload_len,
+    ))
+}
+
+/// Whether an undecodable entry is the unfinished last write: it claims to extend past the end of the file.
+/// A final entry that is all there but fails its checksum is corruption, not a torn write.
+fn is_torn_tail(bytes: &[u8]) -> bool {
+    match bytes.get(0..4) {
+        Some(len_bytes) => {
+            let payload_len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+            ENTRY_HEADER_LEN + payload_len > bytes.len()
+        }
+        // Not even a full length prefix made it to disk:
+        None => true,
+    }
+}
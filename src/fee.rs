@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+use crate::{DECIMAL_ACCURACY, DecimalType, transaction::TransactionKind};
+
+/// A fee on one kind of transaction: a flat part plus a percentage of the amount, kept within `min` and `max`.
+/// Given as comma separated parts in any order, e.g. `0.5`, `1%`, or `0.25,1.5%,min=0.5,max=20`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Fee {
+    flat: DecimalType,
+    /// Percent of the amount, e.g. `1.5` for 1.5%.
+    percent: DecimalType,
+    min: Option<DecimalType>,
+    max: Option<DecimalType>,
+}
+
+impl Fee {
+    /// The fee on `amount`, rounded half to even to `DECIMAL_ACCURACY` places, `None` on overflow.
+    pub fn charge(&self, amount: DecimalType) -> Option<DecimalType> {
+        let fee = self.flat.checked_add(percentage(amount, self.percent)?)?;
+        let fee = self.min.map_or(fee, |min| fee.max(min));
+        Some(self.max.map_or(fee, |max| fee.min(max)))
+    }
+}
+
+impl FromStr for Fee {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fee = Fee::default();
+        let (mut flat, mut percent) = (None, None);
+        for part in s.split(',').map(str::trim) {
+            let (slot, value) = if let Some(min) = part.strip_prefix("min=") {
+                (&mut fee.min, min)
+            } else if let Some(max) = part.strip_prefix("max=") {
+                (&mut fee.max, max)
+            } else if let Some(part_percent) = part.strip_suffix('%') {
+                (&mut percent, part_percent)
+            } else {
+                (&mut flat, part)
+            };
+            let value = value
+                .trim()
+                .parse::<DecimalType>()
+                .map_err(|e| format!("invalid fee part '{part}': {e}"))?;
+            if value < DecimalType::ZERO {
+                return Err(format!("fee part '{part}' is negative"));
+            }
+            if slot.replace(value.round_dp(DECIMAL_ACCURACY)).is_some() {
+                return Err(format!("fee part '{part}' is given more than once"));
+            }
+        }
+        if let (Some(min), Some(max)) = (fee.min, fee.max) {
+            if min > max {
+                return Err(format!("fee minimum {min} is over its maximum {max}"));
+            }
+        }
+        fee.flat = flat.unwrap_or_default();
+        fee.percent = percent.unwrap_or_default();
+        Ok(fee)
+    }
+}
+
+/// The fee for each kind of transaction, charged to the client whose funds it moves.
+/// Receiving a transfer is free, as its sender pays the transfer fee.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FeeSchedule {
+    pub deposit: Option<Fee>,
+    pub withdrawal: Option<Fee>,
+    pub transfer: Option<Fee>,
+}
+
+impl FeeSchedule {
+    pub fn fee_for(&self, kind: &TransactionKind) -> Option<&Fee> {
+        match kind {
+            TransactionKind::Deposit { .. } => self.deposit.as_ref(),
+            TransactionKind::Withdrawal { .. } => self.withdrawal.as_ref(),
+            TransactionKind::TransferOut { .. } => self.transfer.as_ref(),
+            TransactionKind::TransferIn { .. } => None,
+        }
+    }
+}
+
+/// `percent`% of `amount`, rounded once half to even to `DECIMAL_ACCURACY` places, `None` on overflow.
+#[cfg(not(feature = "fixed-point"))]
+fn percentage(amount: DecimalType, percent: DecimalType) -> Option<DecimalType> {
+    Some(
+        amount
+            .checked_mul(percent)?
+            .checked_div(DecimalType::ONE_HUNDRED)?
+            .round_dp(DECIMAL_ACCURACY),
+    )
+}
+
+#[cfg(feature = "fixed-point")]
+fn percentage(amount: DecimalType, percent: DecimalType) -> Option<DecimalType> {
+    amount.checked_percentage(percent)
+}
@@ -0,0 +1,250 @@
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::DECIMAL_ACCURACY;
+
+/// `10^DECIMAL_ACCURACY`, the number of raw units in 1.
+const SCALE: i64 = 10_i64.pow(DECIMAL_ACCURACY);
+
+/// Fixed-point decimal stored as an `i64` count of `10^-DECIMAL_ACCURACY` units,
+/// giving a range of roughly ±922 trillion at 4dp.
+///
+/// A drop-in for the subset of `rust_decimal::Decimal` the engine uses, selected with the `fixed-point` feature.
+/// Like `Decimal`, the operators panic on overflow and the `checked_*` methods don't.
+/// Parsing rounds anything past `DECIMAL_ACCURACY` places half to even, the same rounding `Decimal::round_dp`
+/// applies at output, so both backends agree on inputs with excess precision.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FixedPoint(i64);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseFixedPointError {
+    #[error("invalid decimal '{0}'")]
+    Invalid(String),
+    #[error("decimal '{0}' out of range for a fixed-point i64")]
+    OutOfRange(String),
+}
+
+impl FixedPoint {
+    pub const ZERO: FixedPoint = FixedPoint(0);
+    /// Mirrors `Decimal::MAX`, for testing the overflow checks.
+    #[cfg(test)]
+    pub const MAX: FixedPoint = FixedPoint(i64::MAX);
+
+    pub fn checked_add(self, other: FixedPoint) -> Option<FixedPoint> {
+        self.0.checked_add(other.0).map(FixedPoint)
+    }
+
+    pub fn checked_sub(self, other: FixedPoint) -> Option<FixedPoint> {
+        self.0.checked_sub(other.0).map(FixedPoint)
+    }
+
+    /// `percent`% of `self`, rounded once half to even like `Decimal` would round the exact result.
+    pub fn checked_percentage(self, percent: FixedPoint) -> Option<FixedPoint> {
+        let divisor = i128::from(SCALE) * 100;
+        let product = i128::from(self.0) * i128::from(percent.0);
+        let (quotient, remainder) = (product / divisor, (product % divisor).abs());
+        let round_away = remainder * 2 > divisor || (remainder * 2 == divisor && quotient % 2 != 0);
+        let rounded = if round_away {
+            quotient + product.signum()
+        } else {
+            quotient
+        };
+        i64::try_from(rounded).ok().map(FixedPoint)
+    }
+
+    /// A no-op, for parity with `rust_decimal`: there is no scale to strip, and `Display` already prints
+    /// what `rust_decimal` prints normalized.
+    #[cfg(test)]
+    pub fn normalize(self) -> FixedPoint {
+        self
+    }
+
+    /// Round half to even to `dp` decimal places, a no-op at or above `DECIMAL_ACCURACY`.
+    pub fn round_dp(self, dp: u32) -> FixedPoint {
+        if dp >= DECIMAL_ACCURACY {
+            return self;
+        }
+        let step = 10_i64.pow(DECIMAL_ACCURACY - dp);
+        let truncated = self.0 / step * step;
+        let remainder = (self.0 - truncated).abs();
+        let round_away =
+            remainder * 2 > step || (remainder * 2 == step && (truncated / step) % 2 != 0);
+        if round_away {
+            FixedPoint(truncated + step * self.0.signum())
+        } else {
+            FixedPoint(truncated)
+        }
+    }
+}
+
+impl From<i32> for FixedPoint {
+    fn from(value: i32) -> Self {
+        // Can't overflow, i32::MAX * 10^4 is well within i64:
+        FixedPoint(i64::from(value) * SCALE)
+    }
+}
+
+impl From<u16> for FixedPoint {
+    fn from(value: u16) -> Self {
+        FixedPoint(i64::from(value) * SCALE)
+    }
+}
+
+impl Add for FixedPoint {
+    type Output = FixedPoint;
+
+    fn add(self, other: FixedPoint) -> FixedPoint {
+        self.checked_add(other)
+            .expect("FixedPoint addition overflowed")
+    }
+}
+
+impl Sub for FixedPoint {
+    type Output = FixedPoint;
+
+    fn sub(self, other: FixedPoint) -> FixedPoint {
+        self.checked_sub(other)
+            .expect("FixedPoint subtraction overflowed")
+    }
+}
+
+impl AddAssign for FixedPoint {
+    fn add_assign(&mut self, other: FixedPoint) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for FixedPoint {
+    fn sub_assign(&mut self, other: FixedPoint) {
+        *self = *self - other;
+    }
+}
+
+impl AddAssign<&FixedPoint> for FixedPoint {
+    fn add_assign(&mut self, other: &FixedPoint) {
+        *self = *self + *other;
+    }
+}
+
+impl SubAssign<&FixedPoint> for FixedPoint {
+    fn sub_assign(&mut self, other: &FixedPoint) {
+        *self = *self - *other;
+    }
+}
+
+impl FromStr for FixedPoint {
+    type Err = ParseFixedPointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseFixedPointError::Invalid(s.to_string());
+        let out_of_range = || ParseFixedPointError::OutOfRange(s.to_string());
+
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int_digits, frac_digits) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if (int_digits.is_empty() && frac_digits.is_empty())
+            || !int_digits.bytes().all(|b| b.is_ascii_digit())
+            || !frac_digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let mut units: i64 = 0;
+        for digit in int_digits.bytes() {
+            units = units
+                .checked_mul(10)
+                .and_then(|u| u.checked_add(i64::from(digit - b'0')))
+                .ok_or_else(out_of_range)?;
+        }
+        units = units.checked_mul(SCALE).ok_or_else(out_of_range)?;
+
+        let accuracy = DECIMAL_ACCURACY as usize;
+        let (kept, excess) = frac_digits.split_at(frac_digits.len().min(accuracy));
+        let mut frac_units: i64 = 0;
+        for position in 0..accuracy {
+            let digit = kept.as_bytes().get(position).map_or(0, |b| b - b'0');
+            frac_units = frac_units * 10 + i64::from(digit);
+        }
+        units = units.checked_add(frac_units).ok_or_else(out_of_range)?;
+
+        // Round half to even on the excess digits:
+        let mut excess_digits = excess.bytes().map(|b| b - b'0');
+        let round_up = match excess_digits.next() {
+            Some(first) if first > 5 => true,
+            Some(5) => excess_digits.any(|d| d != 0) || units % 2 != 0,
+            _ => false,
+        };
+        if round_up {
+            units = units.checked_add(1).ok_or_else(out_of_range)?;
+        }
+
+        Ok(FixedPoint(if negative { -units } else { units }))
+    }
+}
+
+impl fmt::Display for FixedPoint {
+    /// As `rust_decimal` prints a normalized value: trailing zeros are trimmed and zero is unsigned,
+    /// e.g. `1.5`, `2`, `-0.0001`, `0`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        write!(f, "{sign}{}", units / scale)?;
+        let frac = format!(
+            "{:0width$}",
+            units % scale,
+            width = DECIMAL_ACCURACY as usize
+        );
+        let frac = frac.trim_end_matches('0');
+        if !frac.is_empty() {
+            write!(f, ".{frac}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for FixedPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FixedPoint {
+    /// Accepts strings and numbers, mirroring `rust_decimal`, floats go through their shortest string form.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(FixedPointVisitor)
+    }
+}
+
+struct FixedPointVisitor;
+
+impl de::Visitor<'_> for FixedPointVisitor {
+    type Value = FixedPoint;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a decimal number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<FixedPoint, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<FixedPoint, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<FixedPoint, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<FixedPoint, E> {
+        self.visit_str(&v.to_string())
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use error_stack::Report;
+use futures::StreamExt;
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+
+use crate::{
+    app_error::AppError,
+    client::ClientId,
+    csv::{self, InputOptions, InputRecord, InputRow, InputRows, RowAck},
+    engine::EngineSender,
+    transaction::{Timestamp, TransactionId},
+};
+
+/// The fields of a json input line, any others are flagged like extra csv columns.
+const FIELDS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "currency",
+    "to",
+    "timestamp",
+];
+
+/// Format of the transaction input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+    /// Rows under a `type, client, tx, amount` header.
+    Csv,
+    /// One json object per line with `type`, `client`, `tx` and optionally `amount` fields.
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Guess from the file extension, `.jsonl` and `.ndjson` are json lines and anything else csv.
+    pub fn from_path(path: &Path) -> InputFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl" | "ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+
+    /// Read the rows of `input`, for `csv::process_rows` or `csv::validate_rows`.
+    pub async fn read_rows<'a>(
+        self,
+        input: impl AsyncRead + Unpin + Send + 'a,
+    ) -> Result<InputRows<'a>, Report<AppError>> {
+        match self {
+            InputFormat::Csv => csv::read_rows(input).await,
+            InputFormat::Jsonl => Ok(read_rows(input)),
+        }
+    }
+
+    /// Stream `input` into the engine, see `csv::process_rows` for acknowledgements.
+    pub async fn process_input(
+        self,
+        engine: &EngineSender,
+        input: impl AsyncRead + Unpin + Send,
+        source: &str,
+        options: InputOptions,
+        verbose: bool,
+        ack_tx: Option<tokio::sync::mpsc::Sender<RowAck>>,
+    ) -> Result<(), Report<AppError>> {
+        let rows = self.read_rows(input).await?;
+        csv::process_rows(engine, rows, source, options, verbose, ack_tx).await
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonInputRecord {
+    #[serde(rename = "type", deserialize_with = "csv::deserialize_lowercase")]
+    record_type: String,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(rename = "tx")]
+    txid: TransactionId,
+    #[serde(default, deserialize_with = "deserialize_json_amount")]
+    amount: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    to: Option<ClientId>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+}
+
+impl From<JsonInputRecord> for InputRecord {
+    fn from(record: JsonInputRecord) -> Self {
+        InputRecord {
+            record_type: record.record_type,
+            client_id: record.client_id,
+            txid: record.txid,
+            amount: record.amount,
+            currency: record.currency,
+            to: record.to,
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+/// Amounts can be strings or json numbers, which are taken as their shortest round-trip string,
+/// so are exact up to around 15 significant digits. Missing or `null` is `None`.
+fn deserialize_json_amount<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JsonAmount {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(
+        Option::<JsonAmount>::deserialize(deserializer)?.map(|amount| match amount {
+            JsonAmount::String(amount) => amount,
+            JsonAmount::Number(amount) => amount.to_string(),
+        }),
+    )
+}
+
+/// Read the json lines of `input` as rows, following exactly the same rules as csv rows.
+/// Blank lines are ignored, and don't count as rows.
+pub fn read_rows<'a>(input: impl AsyncRead + Unpin + Send + 'a) -> InputRows<'a> {
+    let lines = tokio::io::BufReader::new(input).lines();
+    futures::stream::unfold(lines, |mut lines| async move {
+        loop {
+            let row = match lines.next_line().await {
+                Err(error) => Err(Report::new(error).change_context(AppError)),
+                Ok(None) => return None,
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => Ok(parse_line(&line)),
+            };
+            return Some((row, lines));
+        }
+    })
+    .boxed()
+}
+
+fn parse_line(line: &str) -> InputRow {
+    let fields = match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(line) {
+        Ok(fields) => fields,
+        Err(error) => return InputRow::Malformed(error.to_string()),
+    };
+    let extra_columns = fields.keys().any(|field| !FIELDS.contains(&field.as_str()));
+    match serde_json::from_value::<JsonInputRecord>(serde_json::Value::Object(fields)) {
+        Ok(record) => InputRow::Record {
+            record: record.into(),
+            extra_columns,
+        },
+        Err(error) => InputRow::Malformed(error.to_string()),
+    }
+}
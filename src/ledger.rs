@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
+
+use crate::{
+    DecimalType,
+    app_error::AppError,
+    client::{ClientId, HistoryKind},
+    engine::EngineState,
+    engine_error::EngineError,
+    serialize_decimal,
+    transaction::TransactionId,
+};
+
+/// An asset balances are held in, e.g. `USD` or `BTC`, upper cased.
+/// The default, with an empty code, is the asset of every input row without a currency.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Currency(String);
+
+/// The default asset, for borrowing where there's no `Currency` to hand.
+pub static DEFAULT_CURRENCY: Currency = Currency(String::new());
+
+impl Currency {
+    pub fn new(code: &str) -> Self {
+        Currency(code.trim().to_uppercase())
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_default() {
+            write!(f, "the default currency")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// An account in the double-entry books, each asset having its own set.
+/// `Available` and `Held` belong to the client the transfer is posted for, the rest to the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Account {
+    Available,
+    Held,
+    /// The platform's side of money entering through deposits and leaving through withdrawals.
+    Clearing,
+    /// Deposits reversed back to the card network by chargebacks.
+    ChargebackLoss,
+    /// The platform's side of transfers between clients, zero once both sides of each transfer are posted.
+    Transfers,
+    /// The house account, credited with every fee charged and debited with every fee refunded.
+    House,
+}
+
+/// `amount` moved from one account to another, the only way any balance changes.
+/// Debiting and crediting the same amount keeps the books summing to zero.
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+    pub from: Account,
+    pub to: Account,
+    pub amount: DecimalType,
+}
+
+/// What one step of an event posted for a client: its transfer, and any fee charged or refunded alongside it.
+#[derive(Debug, Clone, Copy)]
+pub struct Posting {
+    pub transfer: Transfer,
+    pub fee: Option<Transfer>,
+}
+
+impl Posting {
+    /// The fee charged to the client, negative when refunded.
+    pub fn fee_charged(&self) -> DecimalType {
+        match self.fee {
+            Some(fee) if fee.to == Account::House => fee.amount,
+            Some(fee) => DecimalType::ZERO - fee.amount,
+            None => DecimalType::ZERO,
+        }
+    }
+}
+
+impl From<Transfer> for Posting {
+    fn from(transfer: Transfer) -> Self {
+        Posting {
+            transfer,
+            fee: None,
+        }
+    }
+}
+
+/// A client's accounts in one asset, whose balances are the client's available and held funds.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ClientAccounts {
+    pub available: DecimalType,
+    pub held: DecimalType,
+}
+
+impl ClientAccounts {
+    /// Never overflows, as `Ledger::post` only commits balances whose total is representable.
+    pub fn total(&self) -> DecimalType {
+        self.held + self.available
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct PlatformAccounts {
+    clearing: DecimalType,
+    chargeback_loss: DecimalType,
+    #[serde(default)]
+    transfers: DecimalType,
+    #[serde(default)]
+    house: DecimalType,
+}
+
+/// A posted transfer and the event that caused it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct JournalEntry {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "type")]
+    pub kind: HistoryKind,
+    #[serde(rename = "tx")]
+    pub txid: TransactionId,
+    /// Empty for the default asset.
+    #[cfg_attr(test, serde(default))]
+    pub currency: Currency,
+    pub from: Account,
+    pub to: Account,
+    #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
+    pub amount: DecimalType,
+}
+
+/// The platform's accounts in each asset, plus the journal of every posted transfer when the engine records it.
+/// Client accounts live on each `ClientState`, and only change through `Ledger::post`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Ledger {
+    platform: BTreeMap<Currency, PlatformAccounts>,
+    journal: Vec<JournalEntry>,
+}
+
+impl Ledger {
+    /// Apply `posting` in `currency` to the books, for the client owning `client`, its accounts in that currency.
+    /// Its transfer and fee post together, every balance is checked before any changes,
+    /// so a failed post leaves the books untouched.
+    pub fn post(
+        &mut self,
+        client: &mut ClientAccounts,
+        currency: &Currency,
+        posting: impl Into<Posting>,
+        txid: TransactionId,
+    ) -> Result<(), Report<EngineError>> {
+        let Posting { transfer, fee } = posting.into();
+        let mut client_after = *client;
+        let mut platform_after = self.platform.get(currency).copied().unwrap_or_default();
+        let overflow = || Report::from(EngineError::BalanceOverflow(txid));
+
+        for transfer in std::iter::once(transfer).chain(fee) {
+            let from = balance_mut(transfer.from, &mut client_after, &mut platform_after);
+            *from = from.checked_sub(transfer.amount).ok_or_else(overflow)?;
+            let to = balance_mut(transfer.to, &mut client_after, &mut platform_after);
+            *to = to.checked_add(transfer.amount).ok_or_else(overflow)?;
+        }
+
+        // Should be impossible, only open disputes put funds in held and each releases at most what it put in:
+        if client_after.held < DecimalType::ZERO {
+            return Err(Report::from(EngineError::InternalError).attach(format!(
+                "Held funds {} less than {} moved out of them for txid {txid}",
+                client.held, transfer.amount
+            )));
+        }
+        // The client's total must stay representable too, as it's computed on every output:
+        client_after
+            .available
+            .checked_add(client_after.held)
+            .ok_or_else(overflow)?;
+
+        *client = client_after;
+        self.platform.insert(currency.clone(), platform_after);
+        Ok(())
+    }
+
+    pub fn record(&mut self, entry: JournalEntry) {
+        self.journal.push(entry);
+    }
+
+    /// Take over another shard's books, whose clients are disjoint from this one's.
+    pub fn merge(&mut self, other: Ledger) -> Result<(), Report<EngineError>> {
+        let overflow = || {
+            Report::from(EngineError::InternalError)
+                .attach("Platform accounts overflowed merging shards")
+        };
+        for (currency, other_platform) in other.platform {
+            let platform = self.platform.entry(currency).or_default();
+            platform.clearing = platform
+                .clearing
+                .checked_add(other_platform.clearing)
+                .ok_or_else(overflow)?;
+            platform.chargeback_loss = platform
+                .chargeback_loss
+                .checked_add(other_platform.chargeback_loss)
+                .ok_or_else(overflow)?;
+            // Each shard holds one side of a transfer between shards, which cancel out here:
+            platform.transfers = platform
+                .transfers
+                .checked_add(other_platform.transfers)
+                .ok_or_else(overflow)?;
+            platform.house = platform
+                .house
+                .checked_add(other_platform.house)
+                .ok_or_else(overflow)?;
+        }
+        self.journal.extend(other.journal);
+        Ok(())
+    }
+}
+
+fn balance_mut<'a>(
+    account: Account,
+    client: &'a mut ClientAccounts,
+    platform: &'a mut PlatformAccounts,
+) -> &'a mut DecimalType {
+    match account {
+        Account::Available => &mut client.available,
+        Account::Held => &mut client.held,
+        Account::Clearing => &mut platform.clearing,
+        Account::ChargebackLoss => &mut platform.chargeback_loss,
+        Account::Transfers => &mut platform.transfers,
+        Account::House => &mut platform.house,
+    }
+}
+
+/// Prove the books balance: in each asset, every account of every client plus the platform accounts
+/// must sum to zero. A failure means a balance changed without a matching transfer, so is a hard error.
+pub fn trial_balance(state: &EngineState) -> Result<(), Report<AppError>> {
+    let mut sums = BTreeMap::<&Currency, DecimalType>::new();
+    let client_balances = state
+        .all_clients_state()
+        .iter()
+        .flat_map(|(_, client)| client.balances())
+        .flat_map(|(currency, accounts)| {
+            [(currency, accounts.available), (currency, accounts.held)]
+        });
+    let platform_balances = state
+        .ledger()
+        .platform
+        .iter()
+        .flat_map(|(currency, platform)| {
+            [
+                (currency, platform.clearing),
+                (currency, platform.chargeback_loss),
+                (currency, platform.transfers),
+                (currency, platform.house),
+            ]
+        });
+    for (currency, balance) in client_balances.chain(platform_balances) {
+        let sum = sums.entry(currency).or_default();
+        *sum = sum.checked_add(balance).ok_or_else(|| {
+            Report::new(AppError).attach(format!("Trial balance overflowed in {currency}"))
+        })?;
+    }
+    for (currency, sum) in sums {
+        if sum != DecimalType::ZERO {
+            let platform = state
+                .ledger()
+                .platform
+                .get(currency)
+                .copied()
+                .unwrap_or_default();
+            return Err(Report::new(AppError).attach(format!(
+                "Trial balance failed in {currency}, the books sum to {sum} with clearing {}, chargeback loss {}, transfers {} and house {}",
+                platform.clearing, platform.chargeback_loss, platform.transfers, platform.house
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Write the journal as csv, grouped by client id with each client's transfers in the order they were posted,
+/// so a sharded engine exports the same journal as a single one.
+pub async fn output_journal(
+    ledger: &Ledger,
+    writer: impl AsyncWrite + Unpin,
+) -> Result<(), Report<AppError>> {
+    let mut entries = ledger.journal.iter().collect::<Vec<_>>();
+    // Stable, so each client's own order is kept:
+    entries.sort_by_key(|entry| entry.client_id);
+
+    let mut wtr = csv_async::AsyncSerializer::from_writer(writer);
+    for entry in entries {
+        wtr.serialize(entry).await.change_context(AppError)?;
+    }
+    wtr.flush().await.change_context(AppError)?;
+    Ok(())
+}
@@ -1,30 +1,286 @@
 use clap::Parser;
 use error_stack::{Report, ResultExt};
+use futures::StreamExt;
 
+mod admin;
 mod app_error;
 mod client;
 mod csv;
 mod engine;
 mod engine_error;
+mod event_log;
+mod fee;
+#[cfg(feature = "fixed-point")]
+mod fixed_point;
+mod jsonl;
+mod ledger;
+mod output;
+mod risk;
+mod server;
+mod sharded_engine;
+mod snapshot;
+mod statement;
 mod transaction;
 
 /// Type aliasing to allow easier switchout of decimal type if needed.
+#[cfg(not(feature = "fixed-point"))]
 type DecimalType = rust_decimal::Decimal;
+/// Fixed-point `i64` backend, enabled with `--features fixed-point`.
+#[cfg(feature = "fixed-point")]
+type DecimalType = fixed_point::FixedPoint;
 
 /// The accuracy the input and ouput should be precise to.
 const DECIMAL_ACCURACY: u32 = 4;
 
-#[derive(Parser)]
+/// Serialize a decimal as a string rounded to `DECIMAL_ACCURACY`, avoiding any float conversion.
+fn serialize_decimal<S>(dec: &DecimalType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&dec.round_dp(DECIMAL_ACCURACY).to_string())
+}
+
+/// As `serialize_decimal`, for a column only written when present.
+fn serialize_optional_decimal<S>(
+    dec: &Option<DecimalType>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match dec {
+        Some(dec) => serialize_decimal(dec, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Deserialize a decimal from its string form.
+/// Deserializing `DecimalType` directly lets csv infer a float first, losing precision on large amounts.
+#[cfg(test)]
+fn deserialize_decimal<'de, D>(deserializer: D) -> Result<DecimalType, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::{Deserialize, de::Error};
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(D::Error::custom)
+}
+
+/// As `deserialize_decimal`, with an empty field as `None`.
+#[cfg(test)]
+fn deserialize_optional_decimal<'de, D>(deserializer: D) -> Result<Option<DecimalType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::{Deserialize, de::Error};
+    Option::<String>::deserialize(deserializer)?
+        .map(|amount| amount.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Buffer between an ingestor and whatever consumes its per-row acknowledgements.
+const ACK_BUFFER_SIZE: usize = 1_000;
+
+#[derive(Parser, Default)]
 #[command(version, about = "Toy Payments Engine")]
 struct Args {
-    /// Path to the CSV file
-    csv_path: std::path::PathBuf,
+    /// Paths to the input files, csv or json lines, processed one after another in the given order.
+    /// `-` reads from stdin.
+    #[arg(
+        value_name = "PATH",
+        required_unless_present = "serve",
+        conflicts_with = "serve"
+    )]
+    inputs: Vec<std::path::PathBuf>,
+
+    /// Instead of reading a file, listen on this address for TCP connections that each stream csv rows.
+    /// On ctrl-c, stops accepting, waits for open connections to close, then outputs the client states.
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<std::net::SocketAddr>,
+
+    /// Format of the input file, or of every connection in serve mode.
+    /// Defaults to json lines for `.jsonl` and `.ndjson` files, otherwise csv.
+    #[arg(long, value_enum)]
+    input_format: Option<jsonl::InputFormat>,
+
+    /// Check every input row before applying any, failing on rows that are malformed, have a negative amount
+    /// or unknown type, give an amount with more than 4 decimal places, or give any amount on a dispute,
+    /// resolve or chargeback row. Holds all input in memory.
+    #[arg(long, conflicts_with_all = ["lenient", "serve"])]
+    strict: bool,
+
+    /// Skip every row that can't be applied as written, including those that are malformed or missing an amount,
+    /// rather than aborting. Skipped rows are reported in `--rejections` and with `--verbose`.
+    #[arg(long)]
+    lenient: bool,
+
+    /// How amounts with more than 4 decimal places are read: kept and only rounded at output, rounded half
+    /// to even, truncated, or rejected. Strict mode always fails on them.
+    #[arg(long, value_enum, default_value_t, conflicts_with = "strict")]
+    precision: csv::PrecisionPolicy,
+
+    /// In serve mode, write each row's outcome back to its connection as a `row, outcome, reason` csv row.
+    #[arg(long, requires = "serve")]
+    ack: bool,
+
+    /// In serve mode, also answer live balance queries on this address while ingesting.
+    /// Each request line (`balance <client>`, `clients` or `tx <client> <tx>`) is answered with a json line.
+    #[arg(long, value_name = "ADDR", requires = "serve")]
+    query_addr: Option<std::net::SocketAddr>,
+
+    /// Also write every input row that was not applied to this csv file, with a stable reason code.
+    #[arg(long, value_name = "PATH", conflicts_with = "serve")]
+    rejections: Option<std::path::PathBuf>,
+
+    /// Durably log every event here before applying it. If the log already exists its events are replayed first,
+    /// and the input is expected to start with those same events, which are skipped rather than re-applied.
+    /// This lets a run that died halfway be restarted with the same input.
+    #[arg(long, value_name = "PATH")]
+    event_log: Option<std::path::PathBuf>,
+
+    /// Start the engine from this snapshot of a previous run's state, processing the input on top of it.
+    #[arg(long, value_name = "PATH", conflicts_with = "event_log")]
+    from_snapshot: Option<std::path::PathBuf>,
+
+    /// Write a snapshot of the entire engine state here once all input has been processed.
+    #[arg(long, value_name = "PATH", conflicts_with = "event_log")]
+    snapshot_out: Option<std::path::PathBuf>,
+
+    /// Also write the snapshot every N processed events, bounding how much is lost if the run dies.
+    #[arg(long, value_name = "N", requires = "snapshot_out")]
+    snapshot_every: Option<std::num::NonZeroU64>,
+
+    /// Partition clients across this many engine tasks, processing independent clients in parallel.
+    /// Output is identical to a single engine.
+    #[arg(long, value_name = "N", conflicts_with_all = ["event_log", "from_snapshot", "snapshot_out"])]
+    shards: Option<std::num::NonZeroUsize>,
+
+    /// Reject any deposit or withdrawal for more than this amount.
+    #[arg(long, value_name = "AMOUNT")]
+    max_tx_amount: Option<DecimalType>,
+
+    /// Reject any deposit that would take a client's total balance over this amount.
+    #[arg(long, value_name = "AMOUNT")]
+    max_balance: Option<DecimalType>,
+
+    /// Which transaction kinds can be disputed.
+    #[arg(long, value_enum, default_value_t)]
+    dispute_policy: client::DisputePolicy,
+
+    /// Reject disputes of transactions more than this many days old, by the rows' timestamps.
+    #[arg(long, value_name = "DAYS")]
+    dispute_window_days: Option<u32>,
+
+    /// Resolve disputes still open this many days after they were opened, by the rows' timestamps.
+    #[arg(long, value_name = "DAYS")]
+    chargeback_deadline_days: Option<u32>,
+
+    /// Fee charged on each deposit, taken from the deposit: a flat amount and/or a percentage, optionally
+    /// kept within a minimum and maximum, e.g. `0.25,1.5%,min=0.5,max=20`.
+    #[arg(long, value_name = "FEE")]
+    deposit_fee: Option<fee::Fee>,
+
+    /// Fee charged on each withdrawal on top of its amount, in the same form as `--deposit-fee`.
+    #[arg(long, value_name = "FEE")]
+    withdrawal_fee: Option<fee::Fee>,
+
+    /// Fee charged to the sender of each transfer on top of its amount, in the same form as `--deposit-fee`.
+    #[arg(long, value_name = "FEE")]
+    transfer_fee: Option<fee::Fee>,
+
+    /// Check every withdrawal against the risk rules in this json file: `max_withdrawal`, `velocity`
+    /// (`max_total` over either the client's last `client_transactions` or the last `seconds`), `max_open_disputes`
+    /// and `min_reserve`. Transfers aren't checked.
+    #[arg(long, value_name = "PATH")]
+    risk_rules: Option<std::path::PathBuf>,
+
+    /// Apply the admin operations in this file (unlock, freeze, close) once the csv input has been processed.
+    /// Its first line must be the admin token. Each row's outcome is written to stderr.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "serve",
+        requires = "admin_token"
+    )]
+    admin: Option<std::path::PathBuf>,
+
+    /// In serve mode, also accept admin operations on this address.
+    /// Each connection must send the admin token as its first line, and gets each row's outcome written back.
+    #[arg(long, value_name = "ADDR", requires_all = ["serve", "admin_token"])]
+    admin_addr: Option<std::net::SocketAddr>,
+
+    /// Token admin input must start with.
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Write why each client is locked in the `locked` output column, rather than `true`/`false`.
+    #[arg(long)]
+    lock_reasons: bool,
+
+    /// Format of the final client states.
+    #[arg(long, value_enum, default_value_t)]
+    output_format: output::OutputFormat,
+
+    /// In the json output formats, also include each client's number of transactions, number of open disputes,
+    /// and when it was locked.
+    #[arg(long)]
+    output_details: bool,
+
+    /// Order of the output rows.
+    #[arg(long, value_enum, default_value_t)]
+    sort: output::OutputSort,
+
+    /// Only output locked clients.
+    #[arg(long)]
+    only_locked: bool,
+
+    /// Only output clients with funds held by a dispute.
+    #[arg(long)]
+    only_held: bool,
+
+    /// Only output clients with ids in this inclusive range, e.g. `100-199`.
+    #[arg(long, value_name = "START-END")]
+    client_range: Option<output::ClientRange>,
+
+    /// Once all input is processed, write a statement of every applied deposit, withdrawal, dispute, resolve
+    /// and chargeback here, with running balances. Turns on history recording, which costs memory per event.
+    #[arg(long, value_name = "PATH")]
+    statement: Option<std::path::PathBuf>,
+
+    /// Only include this client in the statement.
+    #[arg(long, value_name = "CLIENT", requires = "statement")]
+    statement_client: Option<client::ClientId>,
+
+    /// Format of the statement.
+    #[arg(long, value_enum, default_value_t, requires = "statement")]
+    statement_format: statement::StatementFormat,
+
+    /// Once all input is processed, write the ledger journal of every transfer between client and platform
+    /// accounts here. Turns on journal recording, which costs memory per event.
+    #[arg(long, value_name = "PATH")]
+    ledger: Option<std::path::PathBuf>,
 
     /// Enable verbose output, which currently equates to printing various soft client errors to stderr.
     #[arg(short, long)]
     verbose: bool,
 }
 
+impl Args {
+    fn input_options(&self) -> csv::InputOptions {
+        csv::InputOptions {
+            validation: if self.strict {
+                csv::RowValidation::Strict
+            } else if self.lenient {
+                csv::RowValidation::Lenient
+            } else {
+                csv::RowValidation::Default
+            },
+            precision: self.precision,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -39,37 +295,291 @@ async fn main_inner(
     args: &Args,
     writer: impl tokio::io::AsyncWrite + Unpin,
 ) -> Result<(), Report<app_error::AppError>> {
-    let mut engine = engine::spawn_engine(args.verbose);
+    let event_log = match &args.event_log {
+        Some(path) => Some(event_log::EventLog::open(path).await?),
+        None => None,
+    };
+    let initial_state = match &args.from_snapshot {
+        Some(path) => Some(snapshot::read_snapshot(path).await?),
+        None => None,
+    };
+    let risk = match &args.risk_rules {
+        Some(path) => risk::read_risk_rules(path).await?,
+        None => risk::RiskRules::default(),
+    };
+    let engine_config = engine::EngineConfig {
+        verbose: args.verbose,
+        event_log,
+        // TCP connections can't be replayed from the beginning, so only the state is recovered:
+        resume_from_log: args.serve.is_none(),
+        initial_state,
+        snapshot_every: args.snapshot_out.clone().zip(args.snapshot_every),
+        policy: client::AccountPolicy {
+            limits: client::AmountLimits {
+                max_transaction: args.max_tx_amount,
+                max_balance: args.max_balance,
+            },
+            disputes: args.dispute_policy,
+            fees: fee::FeeSchedule {
+                deposit: args.deposit_fee,
+                withdrawal: args.withdrawal_fee,
+                transfer: args.transfer_fee,
+            },
+            risk,
+            deadlines: client::DisputeDeadlines::from_days(
+                args.dispute_window_days,
+                args.chargeback_deadline_days,
+            ),
+        },
+        record_history: args.statement.is_some(),
+        record_journal: args.ledger.is_some(),
+    };
+    let engine = match args.shards {
+        Some(shard_count) => sharded_engine::spawn_sharded_engine(engine_config, shard_count),
+        None => engine::spawn_engine(engine_config),
+    };
 
-    csv::process_input(
-        &mut engine,
-        tokio::fs::File::open(&args.csv_path)
+    if let Some(addr) = args.serve {
+        let listener = tokio::net::TcpListener::bind(addr)
             .await
-            .change_context(app_error::AppError)?,
-        args.verbose,
-    )
-    .await?;
+            .change_context(app_error::AppError)
+            .attach_with(|| format!("Binding TCP listener to {addr}"))?;
+        let query_server = match args.query_addr {
+            Some(query_addr) => {
+                let query_listener = tokio::net::TcpListener::bind(query_addr)
+                    .await
+                    .change_context(app_error::AppError)
+                    .attach_with(|| format!("Binding query listener to {query_addr}"))?;
+                Some(tokio::spawn(server::serve_queries(
+                    query_listener,
+                    engine.sender(),
+                )))
+            }
+            None => None,
+        };
+        let admin_server = match (args.admin_addr, &args.admin_token) {
+            (Some(admin_addr), Some(admin_token)) => {
+                let admin_listener = tokio::net::TcpListener::bind(admin_addr)
+                    .await
+                    .change_context(app_error::AppError)
+                    .attach_with(|| format!("Binding admin listener to {admin_addr}"))?;
+                Some(tokio::spawn(server::serve_admin(
+                    admin_listener,
+                    engine.sender(),
+                    admin_token.clone(),
+                )))
+            }
+            _ => None,
+        };
+        server::serve(
+            listener,
+            engine.sender(),
+            async {
+                // If the signal handler can't be installed, there's no way to shut down gracefully,
+                // so stop accepting immediately rather than serve forever:
+                let _ = tokio::signal::ctrl_c().await;
+            },
+            async {
+                // A second ctrl-c stops waiting for idle clients to close their connections:
+                if tokio::signal::ctrl_c().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            },
+            server::ConnectionOptions {
+                verbose: args.verbose,
+                acknowledge: args.ack,
+                input_format: args.input_format.unwrap_or(jsonl::InputFormat::Csv),
+                input_options: args.input_options(),
+            },
+        )
+        .await?;
+        // Queries and admin operations are only accepted while ingesting, in-flight ones are dropped:
+        for server in [query_server, admin_server].into_iter().flatten() {
+            server.abort();
+        }
+    } else {
+        if let Some(rejections_path) = &args.rejections {
+            let rejections_file = tokio::fs::File::create(rejections_path)
+                .await
+                .change_context(app_error::AppError)
+                .attach_with(|| format!("Creating rejections file {rejections_path:?}"))?;
+            let (ack_tx, ack_rx) = tokio::sync::mpsc::channel(ACK_BUFFER_SIZE);
+            let rejections_writer = tokio::spawn(csv::output_rejections(ack_rx, rejections_file));
+            // The ack sender is dropped once input is processed, letting the writer finish:
+            let input_result = process_inputs(args, &engine.sender(), Some(ack_tx)).await;
+            rejections_writer
+                .await
+                .change_context(app_error::AppError)
+                .attach("rejections writer task panicked")??;
+            input_result?;
+        } else {
+            process_inputs(args, &engine.sender(), None).await?;
+        }
+        if let (Some(admin_path), Some(admin_token)) = (&args.admin, &args.admin_token) {
+            let admin_input = tokio::fs::File::open(admin_path)
+                .await
+                .change_context(app_error::AppError)
+                .attach_with(|| format!("Opening admin input {admin_path:?}"))?;
+            // Stdout is reserved for the final client states:
+            admin::process_admin_input(
+                &engine.sender(),
+                admin_input,
+                admin_token,
+                tokio::io::stderr(),
+            )
+            .await?;
+        }
+    }
 
     let engine_state = engine
         .shutdown()
         .await
         .attach("Shutting down engine failed")?;
 
-    csv::output_client_state(engine_state.all_clients_state(), writer).await?;
+    // Outputs from books that don't balance can't be trusted:
+    ledger::trial_balance(&engine_state)?;
+
+    if let Some(snapshot_path) = &args.snapshot_out {
+        snapshot::write_snapshot(&engine_state, snapshot_path).await?;
+    }
+
+    if let Some(statement_path) = &args.statement {
+        let statement_file = tokio::fs::File::create(statement_path)
+            .await
+            .change_context(app_error::AppError)
+            .attach_with(|| format!("Creating statement file {statement_path:?}"))?;
+        statement::output_statement(
+            engine_state.all_clients_state(),
+            args.statement_client,
+            args.statement_format,
+            statement_file,
+        )
+        .await?;
+    }
+
+    if let Some(ledger_path) = &args.ledger {
+        let ledger_file = tokio::fs::File::create(ledger_path)
+            .await
+            .change_context(app_error::AppError)
+            .attach_with(|| format!("Creating ledger file {ledger_path:?}"))?;
+        ledger::output_journal(engine_state.ledger(), ledger_file).await?;
+    }
+
+    let output_options = output::OutputOptions {
+        format: args.output_format,
+        sort: args.sort,
+        filter: output::OutputFilter {
+            only_locked: args.only_locked,
+            only_held: args.only_held,
+            client_range: args.client_range,
+        },
+        lock_reasons: args.lock_reasons,
+        details: args.output_details,
+    };
+    output::output_client_state(engine_state.all_clients_state(), writer, &output_options).await?;
+
+    Ok(())
+}
+
+/// Stream every input into the engine in the given order, each file finishing before the next starts.
+/// Each is named by its path in rejections and errors, stdin as `stdin`.
+async fn process_inputs(
+    args: &Args,
+    engine: &engine::EngineSender,
+    ack_tx: Option<tokio::sync::mpsc::Sender<csv::RowAck>>,
+) -> Result<(), Report<app_error::AppError>> {
+    let input_options = args.input_options();
+    if input_options.validation == csv::RowValidation::Strict {
+        // Every input is validated before any row is applied, so there's nothing to undo on failure:
+        let mut validated = Vec::with_capacity(args.inputs.len());
+        for path in &args.inputs {
+            let (input, source, input_format) = open_input(args, path).await?;
+            let rows = input_format.read_rows(input).await?;
+            validated.push((
+                csv::validate_rows(rows, &source, input_options.precision).await?,
+                source,
+            ));
+        }
+        for (rows, source) in validated {
+            let rows = futures::stream::iter(rows.into_iter().map(Ok)).boxed();
+            csv::process_rows(
+                engine,
+                rows,
+                &source,
+                input_options,
+                args.verbose,
+                ack_tx.clone(),
+            )
+            .await?;
+        }
+        return Ok(());
+    }
 
+    for path in &args.inputs {
+        let (input, source, input_format) = open_input(args, path).await?;
+        input_format
+            .process_input(
+                engine,
+                input,
+                &source,
+                input_options,
+                args.verbose,
+                ack_tx.clone(),
+            )
+            .await?;
+    }
     Ok(())
 }
 
+/// Open an input path, `-` being stdin, with its name for acks and errors and its format.
+async fn open_input(
+    args: &Args,
+    path: &std::path::Path,
+) -> Result<
+    (
+        Box<dyn tokio::io::AsyncRead + Unpin + Send>,
+        String,
+        jsonl::InputFormat,
+    ),
+    Report<app_error::AppError>,
+> {
+    let input_format = args
+        .input_format
+        .unwrap_or_else(|| jsonl::InputFormat::from_path(path));
+    if path.as_os_str() == "-" {
+        return Ok((
+            Box::new(tokio::io::stdin()),
+            "stdin".to_string(),
+            input_format,
+        ));
+    }
+    let file = tokio::fs::File::open(path)
+        .await
+        .change_context(app_error::AppError)
+        .attach_with(|| format!("Opening input {path:?}"))?;
+    Ok((Box::new(file), path.display().to_string(), input_format))
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::path::PathBuf;
 
+    use clap::Parser;
     use futures::StreamExt;
     use pretty_assertions::assert_eq;
     use rstest::*;
 
-    use crate::{Args, csv::CsvOutputRecord, main_inner};
+    use crate::{
+        Args, DECIMAL_ACCURACY, DecimalType,
+        client::ClientId,
+        csv::{CsvAckRecord, CsvOutputRecord, CsvRejectionRecord},
+        engine::{self, EngineEvent, EventOutcome},
+        ledger::JournalEntry,
+        main_inner,
+        statement::StatementRow,
+        transaction::{Timestamp, TransactionId},
+    };
 
     /// Deserialize the output csv back into records for comparison during testing
     async fn output_csv_to_records(
@@ -88,8 +598,99 @@ mod tests {
         result
     }
 
+    /// Deserialize a statement csv into rows for comparison during testing
+    async fn statement_csv_to_records(
+        csv_contents: impl tokio::io::AsyncRead + Unpin + Send,
+    ) -> Vec<StatementRow> {
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_deserializer(csv_contents);
+
+        reader
+            .deserialize::<StatementRow>()
+            .map(Result::unwrap)
+            .collect()
+            .await
+    }
+
+    /// Spawn a single engine, or a sharded one with `shards` shards, which must behave the same.
+    fn spawn_engine(config: engine::EngineConfig, shards: Option<usize>) -> engine::EngineHandle {
+        match shards {
+            Some(shard_count) => {
+                crate::sharded_engine::spawn_sharded_engine(config, shard_count.try_into().unwrap())
+            }
+            None => engine::spawn_engine(config),
+        }
+    }
+
+    /// A deposit in the default currency.
+    fn deposit(
+        txid: TransactionId,
+        client_id: ClientId,
+        amount: DecimalType,
+        timestamp: Option<Timestamp>,
+    ) -> EngineEvent {
+        EngineEvent::Deposit {
+            txid,
+            client_id,
+            amount,
+            currency: Default::default(),
+            timestamp,
+        }
+    }
+
+    /// Send the events one at a time, waiting for each outcome, and collect each rejection's reason code,
+    /// `None` where the event applied.
+    async fn reason_codes(
+        sender: &engine::EngineSender,
+        events: impl IntoIterator<Item = EngineEvent>,
+    ) -> Vec<Option<&'static str>> {
+        let mut reason_codes = vec![];
+        for event in events {
+            let outcome = sender.send_event_with_reply(event).await.unwrap();
+            reason_codes.push(match outcome.await.unwrap() {
+                EventOutcome::Applied => None,
+                EventOutcome::Rejected(report) => Some(report.current_context().reason_code()),
+            });
+        }
+        reason_codes
+    }
+
+    /// Deserialize a ledger journal csv into entries for comparison during testing
+    async fn journal_csv_to_records(
+        csv_contents: impl tokio::io::AsyncRead + Unpin + Send,
+    ) -> Vec<JournalEntry> {
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_deserializer(csv_contents);
+
+        reader
+            .deserialize::<JournalEntry>()
+            .map(Result::unwrap)
+            .collect()
+            .await
+    }
+
+    /// Deserialize a rejections csv into records for comparison during testing
+    async fn rejections_csv_to_records(
+        csv_contents: impl tokio::io::AsyncRead + Unpin + Send,
+    ) -> Vec<CsvRejectionRecord> {
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .create_deserializer(csv_contents);
+
+        reader
+            .deserialize::<CsvRejectionRecord>()
+            .map(Result::unwrap)
+            .collect()
+            .await
+    }
+
     /// For each test case folder, read input.csv and expected.csv, process through the engine,
-    /// and compare with the expected output. Client order does not matter when comparing.
+    /// and compare with the expected output, rows in order.
+    /// If the folder has an expected_rejections.csv, the rejections report is compared against it too.
+    /// Every case also runs sharded, which must be indistinguishable from a single engine,
+    /// and from the json lines mirror of its input, which must parse to exactly the same events.
     ///
     /// NOTE AI was used to help generate the testcases, which were then reviewed and augmented.
     #[rstest]
@@ -124,7 +725,7 @@ mod tests {
     #[case::zero_amount_transactions("zero_amount_transactions")]
     #[case::max_precision_exactly_four_places("max_precision_exactly_four_places")]
     #[case::precision_rounding_truncation("precision_rounding_truncation")]
-    #[case::dispute_operations_ignore_amount("dispute_operations_ignore_amount")]
+    #[case::dispute_operations_partial_amount("dispute_operations_partial_amount")]
     #[case::max_client_id("max_client_id")]
     #[case::max_transaction_id("max_transaction_id")]
     #[case::resolve_no_client_exists("resolve_no_client_exists")]
@@ -143,81 +744,1626 @@ mod tests {
     #[case::negative_available_prevents_withdrawal("negative_available_prevents_withdrawal")]
     #[case::chargeback_with_negative_available("chargeback_with_negative_available")]
     #[case::resolution_restores_from_negative("resolution_restores_from_negative")]
+    #[case::max_transaction_amount("max_transaction_amount")]
+    #[case::max_balance("max_balance")]
+    #[case::transfer_receiver_disputes_expired("transfer_receiver_disputes_expired")]
+    #[case::large_amounts_exact("large_amounts_exact")]
+    #[case::withdrawal_dispute_holds_funds("withdrawal_dispute_holds_funds")]
+    #[case::withdrawal_dispute_and_resolution("withdrawal_dispute_and_resolution")]
+    #[case::withdrawal_dispute_and_chargeback("withdrawal_dispute_and_chargeback")]
+    #[case::withdrawal_double_dispute_ignored("withdrawal_double_dispute_ignored")]
+    #[case::withdrawal_redispute_after_resolution("withdrawal_redispute_after_resolution")]
+    #[case::withdrawal_resolve_and_chargeback_without_dispute_ignored(
+        "withdrawal_resolve_and_chargeback_without_dispute_ignored"
+    )]
+    #[case::withdrawal_dispute_wrong_client_ignored("withdrawal_dispute_wrong_client_ignored")]
+    #[case::withdrawal_failed_cannot_be_disputed("withdrawal_failed_cannot_be_disputed")]
+    #[case::withdrawal_and_deposit_disputes_mixed("withdrawal_and_deposit_disputes_mixed")]
+    #[case::withdrawal_dispute_held_funds_not_spendable(
+        "withdrawal_dispute_held_funds_not_spendable"
+    )]
+    #[case::withdrawal_dispute_deposits_only_policy("withdrawal_dispute_deposits_only_policy")]
+    #[case::partial_dispute_holds_part("partial_dispute_holds_part")]
+    #[case::partial_disputes_accumulate("partial_disputes_accumulate")]
+    #[case::partial_resolve("partial_resolve")]
+    #[case::partial_chargeback("partial_chargeback")]
+    #[case::partial_dispute_amount_mismatch("partial_dispute_amount_mismatch")]
+    #[case::full_dispute_after_partial_ignored("full_dispute_after_partial_ignored")]
+    #[case::partial_dispute_negative_amount_ignored("partial_dispute_negative_amount_ignored")]
+    #[case::partial_withdrawal_dispute("partial_withdrawal_dispute")]
+    #[case::admin_unlock_after_chargeback("admin_unlock_after_chargeback")]
+    #[case::admin_lock_reasons("admin_lock_reasons")]
+    #[case::admin_freeze_blocks_later_batches("admin_freeze_blocks_later_batches")]
+    #[case::statement_running_balances("statement_running_balances")]
+    #[case::statement_single_client("statement_single_client")]
+    #[case::ledger_journal("ledger_journal")]
+    #[case::ledger_withdrawal_disputes("ledger_withdrawal_disputes")]
+    #[case::output_ordering("output_ordering")]
+    #[case::multi_currency("multi_currency")]
+    #[case::transfers("transfers")]
+    #[case::transfer_receiver_over_limit("transfer_receiver_over_limit")]
+    #[case::fees("fees")]
+    #[case::risk_rules("risk_rules")]
+    #[case::risk_velocity_window("risk_velocity_window")]
+    #[case::dispute_deadlines("dispute_deadlines")]
+    #[case::untimed_rows_among_timed("untimed_rows_among_timed")]
     #[tokio::test]
-    async fn test_csv_inputs(#[case] test_case_name: &str) {
+    async fn test_csv_inputs(
+        #[case] test_case_name: &str,
+        #[values(None, Some(3))] shards: Option<usize>,
+        #[values("input.csv", "input.jsonl")] input_file: &str,
+    ) {
         let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("test_cases")
             .join(test_case_name);
-        let csv_path = test_case_dir.join("input.csv");
+        let csv_path = test_case_dir.join(input_file);
         let expected_path = test_case_dir.join("expected.csv");
+        let expected_rejections_path = test_case_dir.join("expected_rejections.csv");
+        let rejections_file = tempfile::NamedTempFile::new().unwrap();
 
-        let mut buf = vec![];
-        main_inner(
-            &Args {
-                csv_path,
-                verbose: false,
-            },
-            &mut buf,
+        let expected_statement_path = test_case_dir.join("expected_statement.csv");
+        let statement_file = tempfile::NamedTempFile::new().unwrap();
+
+        let expected_ledger_path = test_case_dir.join("expected_ledger.csv");
+        let ledger_file = tempfile::NamedTempFile::new().unwrap();
+
+        // Any extra cli arguments the test case runs with, e.g. limits:
+        let mut extra_args =
+            std::fs::read_to_string(test_case_dir.join("args.txt")).unwrap_or_default();
+        if expected_statement_path.exists() {
+            extra_args += &format!(" --statement {}", statement_file.path().display());
+        }
+        if expected_ledger_path.exists() {
+            extra_args += &format!(" --ledger {}", ledger_file.path().display());
+        }
+        let mut args = Args::try_parse_from(
+            ["toy_payments_engine", csv_path.to_str().unwrap()]
+                .into_iter()
+                .chain(extra_args.split_whitespace()),
         )
-        .await
         .unwrap();
+        args.rejections = Some(rejections_file.path().to_path_buf());
+        let admin_path = test_case_dir.join("admin.csv");
+        if admin_path.exists() {
+            args.admin = Some(admin_path);
+        }
+        args.shards = shards.map(|n| n.try_into().unwrap());
+
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
 
-        let mut output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
+        let output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
 
         // Read expected output
         let expected_file = tokio::fs::File::open(&expected_path)
             .await
             .unwrap_or_else(|e| panic!("Failed to open {:?}: {}", expected_path, e));
 
-        let mut expected_output_records = output_csv_to_records(expected_file).await;
-
-        // Brief says ordering doesn't matter, for testing we'll order by client_id to compare:
-        output_records.sort_by_key(|r| r.client_id());
-        expected_output_records.sort_by_key(|r| r.client_id());
+        let expected_output_records = output_csv_to_records(expected_file).await;
 
+        // Output order is deterministic, so compare verbatim:
         assert_eq!(
             output_records, expected_output_records,
             "actual != expected for test case '{}'. CSV output records did not match expected.",
             test_case_name
         );
+
+        if expected_rejections_path.exists() {
+            let mut rejection_records = rejections_csv_to_records(
+                tokio::fs::File::open(rejections_file.path()).await.unwrap(),
+            )
+            .await;
+            let expected_rejection_records = rejections_csv_to_records(
+                tokio::fs::File::open(&expected_rejections_path)
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            // Expected files are shared by the csv and json lines inputs, so the source is checked separately:
+            for record in &mut rejection_records {
+                assert_eq!(record.take_source(), csv_path.display().to_string());
+            }
+            // Rejections are reported in row order, so no sorting:
+            assert_eq!(
+                rejection_records, expected_rejection_records,
+                "actual != expected for test case '{}'. Rejection records did not match expected.",
+                test_case_name
+            );
+        }
+
+        if expected_statement_path.exists() {
+            let statement_rows = statement_csv_to_records(
+                tokio::fs::File::open(statement_file.path()).await.unwrap(),
+            )
+            .await;
+            let expected_statement_rows = statement_csv_to_records(
+                tokio::fs::File::open(&expected_statement_path)
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            assert_eq!(
+                statement_rows, expected_statement_rows,
+                "actual != expected for test case '{}'. Statement rows did not match expected.",
+                test_case_name
+            );
+        }
+
+        if expected_ledger_path.exists() {
+            let journal_entries =
+                journal_csv_to_records(tokio::fs::File::open(ledger_file.path()).await.unwrap())
+                    .await;
+            let expected_journal_entries =
+                journal_csv_to_records(tokio::fs::File::open(&expected_ledger_path).await.unwrap())
+                    .await;
+            assert_eq!(
+                journal_entries, expected_journal_entries,
+                "actual != expected for test case '{}'. Ledger journal did not match expected.",
+                test_case_name
+            );
+        }
+    }
+
+    /// Both decimal backends must agree on parsing and output rounding
+    #[rstest]
+    #[case("1.5", "1.5")]
+    #[case("0.0001", "0.0001")]
+    #[case("1.00005", "1.0000")]
+    #[case("1.00015", "1.0002")]
+    #[case("1.000051", "1.0001")]
+    #[case("-2.33335", "-2.3334")]
+    #[case("100", "100")]
+    fn test_decimal_parse_and_round(#[case] input: &str, #[case] expected: &str) {
+        let parsed = input.parse::<DecimalType>().unwrap();
+        assert_eq!(
+            parsed.round_dp(DECIMAL_ACCURACY),
+            expected.parse::<DecimalType>().unwrap()
+        );
     }
 
-    /// Confirm CLI binary works directly
+    /// Both decimal backends print a normalized value the same, and fixed-point always prints it normalized
+    #[rstest]
+    #[case("1.0", "1")]
+    #[case("1.50", "1.5")]
+    #[case("100", "100")]
+    #[case("0.0001", "0.0001")]
+    #[case("-0.0001", "-0.0001")]
+    #[case("-0.0", "0")]
+    #[case("-12.3400", "-12.34")]
+    fn test_decimal_display_normalized(#[case] input: &str, #[case] expected: &str) {
+        let parsed = input.parse::<DecimalType>().unwrap();
+        assert_eq!(parsed.normalize().to_string(), expected);
+        #[cfg(feature = "fixed-point")]
+        assert_eq!(parsed.to_string(), expected);
+    }
+
+    /// Output is ordered and filtered as requested, without any sorting before comparing.
+    #[rstest]
+    #[case::default_by_client("", &[1, 2, 3, 4, 5, 6])]
+    #[case::by_total("--sort total", &[2, 6, 1, 5, 4, 3])]
+    #[case::locked_first("--sort locked-first", &[3, 4, 1, 2, 5, 6])]
+    #[case::only_locked("--only-locked", &[3, 4])]
+    #[case::only_held("--only-held", &[2, 6])]
+    #[case::client_range("--client-range 2-4", &[2, 3, 4])]
+    #[case::combined("--only-locked --sort total", &[4, 3])]
+    #[case::nothing_matches("--client-range 5-5 --only-held", &[])]
     #[tokio::test]
-    async fn test_cli() {
-        let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("test_cases")
-            .join("brief_example");
-        let csv_path = test_case_dir.join("input.csv");
-        let expected_path = test_case_dir.join("expected.csv");
+    async fn test_output_sort_and_filters(
+        #[case] extra_args: &str,
+        #[case] expected_client_ids: &[u16],
+        #[values(None, Some(3))] shards: Option<usize>,
+    ) {
+        let csv_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_cases/output_ordering/input.csv");
+        let mut args = Args::try_parse_from(
+            ["toy_payments_engine", csv_path.to_str().unwrap()]
+                .into_iter()
+                .chain(extra_args.split_whitespace()),
+        )
+        .unwrap();
+        args.shards = shards.map(|n| n.try_into().unwrap());
 
-        let output = tokio::process::Command::new("cargo")
-            .args(["run", "--quiet", "--", csv_path.to_str().unwrap()])
-            .output()
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+        let client_ids = output_csv_to_records(std::io::Cursor::new(buf))
             .await
-            .unwrap();
+            .iter()
+            .map(|record| record.client_id())
+            .collect::<Vec<_>>();
+        assert_eq!(client_ids, expected_client_ids);
+    }
+
+    /// Json lines specifics: blank lines, `null` amounts and amounts given as strings,
+    /// with the format chosen by flag rather than extension.
+    #[tokio::test]
+    async fn test_jsonl_input() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            input.path(),
+            r#"{"type": "Deposit", "client": 1, "tx": 1, "amount": "2.5"}
+
+{"type": "deposit", "client": 1, "tx": 2, "amount": 1}
+{"type": "dispute", "client": 1, "tx": 2, "amount": null}
+"#,
+        )
+        .unwrap();
+        let mut args = Args::try_parse_from([
+            "toy_payments_engine",
+            input.path().to_str().unwrap(),
+            "--input-format",
+            "jsonl",
+        ])
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,2.5,1,3.5,false\n"
+        );
+
+        // A deposit without an amount is malformed, just like in csv:
+        std::fs::write(input.path(), r#"{"type": "deposit", "client": 1, "tx": 1}"#).unwrap();
+        args.inputs = vec![input.path().to_path_buf()];
+        assert!(main_inner(&args, &mut vec![]).await.is_err());
+    }
+
+    /// The json formats carry the csv fields with amounts as decimal strings, one array or one object per line.
+    #[rstest]
+    #[case::json("json")]
+    #[case::jsonl("jsonl")]
+    #[tokio::test]
+    async fn test_output_formats(#[case] format: &str) {
+        let csv_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases/large_amounts_exact/input.csv");
+        let args = Args::try_parse_from([
+            "toy_payments_engine",
+            csv_path.to_str().unwrap(),
+            "--output-format",
+            format,
+        ])
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+
+        let clients: Vec<serde_json::Value> = if format == "json" {
+            serde_json::from_slice(&buf).unwrap()
+        } else {
+            buf.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            clients,
+            [
+                serde_json::json!({
+                    "client": 1,
+                    "available": "900000000000000.1233",
+                    "held": "0",
+                    "total": "900000000000000.1233",
+                    "locked": false,
+                }),
+                serde_json::json!({
+                    "client": 2,
+                    "available": "123456789012.3457",
+                    "held": "0",
+                    "total": "123456789012.3457",
+                    "locked": false,
+                }),
+            ]
+        );
+    }
+
+    /// Details are only included on request, with `locked_at` only set for locked clients.
+    #[tokio::test]
+    async fn test_output_details() {
+        let csv_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_cases/output_ordering/input.csv");
+        let args = Args::try_parse_from([
+            "toy_payments_engine",
+            csv_path.to_str().unwrap(),
+            "--output-format",
+            "json",
+            "--output-details",
+            "--lock-reasons",
+        ])
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+        let clients: Vec<serde_json::Value> = serde_json::from_slice(&buf).unwrap();
+
+        let details = clients
+            .iter()
+            .map(|client| {
+                (
+                    client["transactions"].as_u64().unwrap(),
+                    client["open_disputes"].as_u64().unwrap(),
+                    client["locked_at"].as_u64().is_some(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            details,
+            [
+                (1, 0, false),
+                (1, 1, false),
+                (1, 0, true),
+                (2, 0, true),
+                (1, 0, false),
+                (1, 1, false),
+            ]
+        );
+        // Untimed rows happen at the engine clock, which no timed row has moved:
+        assert_eq!(clients[2]["locked_at"], 0);
+        assert_eq!(clients[3]["lock_reason"], "chargeback of tx 7");
+        assert!(clients[0].get("lock_reason").is_none());
+    }
+
+    /// The default output is byte-identical across runs, despite the client states living in a `HashMap`.
+    #[tokio::test]
+    async fn test_output_is_stable() {
+        let args = Args {
+            inputs: vec![
+                PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("test_cases/output_ordering/input.csv"),
+            ],
+            ..Default::default()
+        };
+        let mut first = vec![];
+        main_inner(&args, &mut first).await.unwrap();
+        for _ in 0..5 {
+            let mut again = vec![];
+            main_inner(&args, &mut again).await.unwrap();
+            assert_eq!(again, first);
+        }
+    }
+
+    /// The json statement holds the same rows as the csv one.
+    #[tokio::test]
+    async fn test_statement_json() {
+        let statement_file = tempfile::NamedTempFile::new().unwrap();
+        main_inner(
+            &Args {
+                inputs: vec![
+                    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                        .join("test_cases/statement_single_client/input.csv"),
+                ],
+                statement: Some(statement_file.path().to_path_buf()),
+                statement_client: Some(2),
+                statement_format: crate::statement::StatementFormat::Json,
+                ..Default::default()
+            },
+            &mut vec![],
+        )
+        .await
+        .unwrap();
+
+        let statement: Vec<serde_json::Value> =
+            serde_json::from_slice(&std::fs::read(statement_file.path()).unwrap()).unwrap();
+        assert_eq!(
+            statement.iter().map(|row| &row["type"]).collect::<Vec<_>>(),
+            ["deposit", "withdrawal", "dispute"]
+        );
+        assert_eq!(statement[2]["client"], 2);
+        assert_eq!(statement[2]["state"], "Disputed");
+        assert_eq!(
+            statement[2]["available"]
+                .as_str()
+                .unwrap()
+                .parse::<DecimalType>()
+                .unwrap(),
+            "-1.5".parse().unwrap()
+        );
+    }
+
+    /// Several inputs, including stdin and mixed formats, feed one engine in the given order,
+    /// with each rejection naming its source and its row within that source.
+    #[tokio::test]
+    async fn test_multiple_inputs_and_stdin() {
+        let input_dir = tempfile::tempdir().unwrap();
+        let first = input_dir.path().join("partner_a.csv");
+        std::fs::write(
+            &first,
+            "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 50.0\n",
+        )
+        .unwrap();
+        let last = input_dir.path().join("partner_b.jsonl");
+        std::fs::write(
+            &last,
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1}
+{"type": "withdrawal", "client": 1, "tx": 4, "amount": 3}
+"#,
+        )
+        .unwrap();
+        let rejections_file = tempfile::NamedTempFile::new().unwrap();
 
+        let mut child = tokio::process::Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args([first.as_os_str(), "-".as_ref(), last.as_os_str()])
+            .arg("--rejections")
+            .arg(rejections_file.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        tokio::io::AsyncWriteExt::write_all(
+            &mut stdin,
+            b"type, client, tx, amount\ndeposit, 2, 3, 5.0\ndispute, 2, 99,\n",
+        )
+        .await
+        .unwrap();
+        drop(stdin);
+        let output = child.wait_with_output().await.unwrap();
         assert!(
             output.status.success(),
             "{}",
             String::from_utf8_lossy(&output.stderr)
         );
 
-        let stdout_csv = String::from_utf8_lossy(&output.stdout);
-
-        let mut output_records =
-            output_csv_to_records(std::io::Cursor::new(stdout_csv.as_bytes())).await;
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n1,7.0,0,7.0,false\n2,5.0,0,5.0,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rejections_file.path()).unwrap(),
+            format!(
+                "source,row,type,client,tx,amount,reason\n\
+                 {},1,withdrawal,1,2,50.0,insufficient_funds\n\
+                 stdin,1,dispute,2,99,,tx_not_found\n\
+                 {},0,deposit,1,1,1,tx_already_seen\n",
+                first.display(),
+                last.display()
+            )
+        );
+    }
 
-        let expected_file = tokio::fs::File::open(&expected_path)
-            .await
-            .unwrap_or_else(|e| panic!("Failed to open {:?}: {}", expected_path, e));
-        let mut expected_output_records = output_csv_to_records(expected_file).await;
+    /// Lenient mode skips and reports every bad row, strict mode fails before applying any event.
+    #[rstest]
+    #[case::missing_amount("deposit, 1, 2,", "deposit,1,2,,missing_amount")]
+    #[case::missing_amount_column("deposit, 1, 2", "deposit,1,2,,missing_amount")]
+    #[case::negative_amount("deposit, 1, 2, -1.0", "deposit,1,2,-1.0,negative_amount")]
+    #[case::unknown_type("bogus, 1, 2, 1.0", "bogus,1,2,1.0,unknown_record_type")]
+    #[case::malformed_client("deposit, x, 2, 1.0", ",,,,malformed_row")]
+    #[case::malformed_amount("deposit, 1, 2, 1.0.0", "deposit,1,2,,malformed_row")]
+    #[case::extra_columns("deposit, 1, 2, 1.0, 1.0", "deposit,1,2,1.0,extra_columns")]
+    #[tokio::test]
+    async fn test_row_validation(#[case] bad_row: &str, #[case] rejection: &str) {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            input.path(),
+            format!(
+                "type, client, tx, amount\ndeposit, 1, 1, 10.0\n{bad_row}\nwithdrawal, 1, 3, 2.0\n"
+            ),
+        )
+        .unwrap();
+        let input_path = input.path().to_str().unwrap();
 
-        output_records.sort_by_key(|r| r.client_id());
-        expected_output_records.sort_by_key(|r| r.client_id());
+        let rejections = tempfile::NamedTempFile::new().unwrap();
+        let args = Args::try_parse_from([
+            "toy_payments_engine",
+            input_path,
+            "--lenient",
+            "--rejections",
+            rejections.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
         assert_eq!(
-            output_records, expected_output_records,
-            "actual != expected for CLI test. CSV output records did not match expected."
+            output_csv_to_records(buf.as_slice()).await,
+            output_csv_to_records(&b"client,available,held,total,locked\n1,8,0,8,false\n"[..])
+                .await
+        );
+        let mut rejected =
+            rejections_csv_to_records(tokio::fs::File::open(rejections.path()).await.unwrap())
+                .await;
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].take_source(), input_path);
+        assert_eq!(
+            rejected,
+            rejections_csv_to_records(
+                format!("row,type,client,tx,amount,reason\n1,{rejection}\n").as_bytes()
+            )
+            .await
         );
+
+        // Nothing reaches the engine, so nothing is logged:
+        let event_log = tempfile::NamedTempFile::new().unwrap();
+        let args = Args::try_parse_from([
+            "toy_payments_engine",
+            input_path,
+            "--strict",
+            "--event-log",
+            event_log.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(main_inner(&args, &mut vec![]).await.is_err());
+        assert!(std::fs::read(event_log.path()).unwrap().is_empty());
+    }
+
+    /// Strict mode also holds amounts to 4 decimal places and dispute rows to the brief's amountless form,
+    /// across every input, while lenient mode rounds the amount and treats the dispute as partial.
+    #[rstest]
+    #[case::excess_precision("deposit, 1, 2, 1.00001")]
+    #[case::dispute_amount("dispute, 1, 1, 5.0")]
+    #[tokio::test]
+    async fn test_strict_only_rules(#[case] row: &str) {
+        let valid = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            valid.path(),
+            "type, client, tx, amount\ndeposit, 1, 1, 10.0\n",
+        )
+        .unwrap();
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(input.path(), format!("type, client, tx, amount\n{row}\n")).unwrap();
+        let mut args = Args {
+            inputs: vec![valid.path().to_path_buf(), input.path().to_path_buf()],
+            lenient: true,
+            ..Default::default()
+        };
+        main_inner(&args, &mut vec![]).await.unwrap();
+
+        args.lenient = false;
+        args.strict = true;
+        let event_log = tempfile::NamedTempFile::new().unwrap();
+        args.event_log = Some(event_log.path().to_path_buf());
+        assert!(main_inner(&args, &mut vec![]).await.is_err());
+        assert!(std::fs::read(event_log.path()).unwrap().is_empty());
+
+        // Trailing zeros aren't precision, and an empty amount is no amount:
+        std::fs::write(
+            input.path(),
+            "type, client, tx, amount\ndeposit, 1, 2, 1.000000\ndispute, 1, 1,\nresolve, 1, 1\n",
+        )
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+        assert_eq!(
+            output_csv_to_records(buf.as_slice()).await,
+            output_csv_to_records(&b"client,available,held,total,locked\n1,11,0,11,false\n"[..])
+                .await
+        );
+    }
+
+    /// Excess precision is dealt with as amounts are read, so the stored balances are exactly the ones output,
+    /// unless kept. Kept at full precision, these sum to 3.00022 and are output as 3.0002.
+    #[rstest]
+    #[cfg_attr(
+        not(feature = "fixed-point"),
+        case::keep("keep", "3.0002", "3.00022", "")
+    )]
+    #[cfg_attr(feature = "fixed-point", case::keep("keep", "3.0003", "3.0003", ""))]
+    #[case::round("round", "3.0003", "3.0003", "")]
+    #[case::truncate("truncate", "3.0001", "3.0001", "")]
+    #[case::reject("reject", "0", "0", "excess_precision")]
+    #[tokio::test]
+    async fn test_precision_policy(
+        #[case] policy: &str,
+        #[case] expected_total: &str,
+        #[case] expected_stored: &str,
+        #[case] rejection_reason: &str,
+    ) {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            input.path(),
+            "type, client, tx, amount\ndeposit, 1, 1, 1.00015\ndeposit, 1, 2, 2.00007\ndeposit, 1, 3, 0\n",
+        )
+        .unwrap();
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let snapshot_path = snapshot_dir.path().join("snapshot.json");
+        let rejections = tempfile::NamedTempFile::new().unwrap();
+        let args = Args::try_parse_from([
+            "toy_payments_engine",
+            input.path().to_str().unwrap(),
+            "--precision",
+            policy,
+            "--snapshot-out",
+            snapshot_path.to_str().unwrap(),
+            "--rejections",
+            rejections.path().to_str().unwrap(),
+        ])
+        .unwrap();
+        let mut buf = vec![];
+        main_inner(&args, &mut buf).await.unwrap();
+
+        let output = String::from_utf8(buf).unwrap();
+        let total = output.lines().nth(1).unwrap().split(',').nth(3).unwrap();
+        let snapshot: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&snapshot_path).unwrap()).unwrap();
+        let stored = &snapshot["state"]["all_clients_state"]["1"]["accounts"][""]["available"];
+        assert_eq!(
+            stored.as_str().unwrap().parse::<DecimalType>().unwrap(),
+            expected_stored.parse::<DecimalType>().unwrap()
+        );
+        assert_eq!(
+            total.parse::<DecimalType>().unwrap(),
+            expected_total.parse::<DecimalType>().unwrap()
+        );
+
+        let reasons = std::fs::read_to_string(rejections.path())
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.rsplit(',').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let expected_reasons = match rejection_reason {
+            "" => vec![],
+            reason => vec![reason; 2],
+        };
+        assert_eq!(reasons, expected_reasons);
+    }
+
+    /// Confirm CLI binary works directly
+    #[tokio::test]
+    async fn test_cli() {
+        let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases")
+            .join("brief_example");
+        let csv_path = test_case_dir.join("input.csv");
+        let expected_path = test_case_dir.join("expected.csv");
+
+        let output = tokio::process::Command::new("cargo")
+            .args(["run", "--quiet", "--", csv_path.to_str().unwrap()])
+            .output()
+            .await
+            .unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let stdout_csv = String::from_utf8_lossy(&output.stdout);
+
+        let output_records =
+            output_csv_to_records(std::io::Cursor::new(stdout_csv.as_bytes())).await;
+
+        let expected_file = tokio::fs::File::open(&expected_path)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to open {:?}: {}", expected_path, e));
+        let expected_output_records = output_csv_to_records(expected_file).await;
+
+        assert_eq!(
+            output_records, expected_output_records,
+            "actual != expected for CLI test. CSV output records did not match expected."
+        );
+    }
+
+    /// Several concurrent TCP connections feed one engine, and shutdown drains them all before output.
+    #[tokio::test]
+    async fn test_serve_multiple_connections() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::server::serve(
+            listener,
+            engine.sender(),
+            async {
+                shutdown_rx.await.ok();
+            },
+            std::future::pending(),
+            crate::server::ConnectionOptions {
+                verbose: false,
+                acknowledge: true,
+                input_format: crate::jsonl::InputFormat::Csv,
+                input_options: Default::default(),
+            },
+        ));
+
+        // Client 1's rows over one connection, client 2's over another, both kept open until after shutdown:
+        let mut conns = vec![];
+        for first_rows in [
+            "deposit, 1, 1, 1.0\ndeposit, 1, 3, 2.0\n",
+            "deposit, 2, 2, 2.0\n",
+        ] {
+            let (read_half, mut write_half) = tokio::net::TcpStream::connect(addr)
+                .await
+                .unwrap()
+                .into_split();
+            write_half
+                .write_all(format!("type, client, tx, amount\n{first_rows}").as_bytes())
+                .await
+                .unwrap();
+            // Ack header and first ack, proving the connection has been accepted before shutdown:
+            let mut acks = tokio::io::BufReader::new(read_half).lines();
+            acks.next_line().await.unwrap().unwrap();
+            acks.next_line().await.unwrap().unwrap();
+            conns.push((acks, write_half));
+        }
+        shutdown_tx.send(()).unwrap();
+        for ((mut acks, mut write_half), last_row) in conns
+            .into_iter()
+            .zip(["withdrawal, 1, 4, 1.5\n", "withdrawal, 2, 5, 3.0\n"])
+        {
+            write_half.write_all(last_row.as_bytes()).await.unwrap();
+            write_half.shutdown().await.unwrap();
+            while acks.next_line().await.unwrap().is_some() {}
+        }
+        server.await.unwrap().unwrap();
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let mut buf = vec![];
+        crate::csv::output_client_state(
+            engine_state.all_clients_state(),
+            &mut buf,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
+
+        let expected_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases")
+            .join("brief_example")
+            .join("expected.csv");
+        let expected_output_records =
+            output_csv_to_records(tokio::fs::File::open(&expected_path).await.unwrap()).await;
+
+        assert_eq!(output_records, expected_output_records);
+    }
+
+    /// With acks enabled, each row's outcome, including typed rejections, is written back to the submitter.
+    #[tokio::test]
+    async fn test_serve_acknowledgements() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::server::serve(
+            listener,
+            engine.sender(),
+            async {
+                shutdown_rx.await.ok();
+            },
+            std::future::pending(),
+            crate::server::ConnectionOptions {
+                verbose: false,
+                acknowledge: true,
+                input_format: crate::jsonl::InputFormat::Csv,
+                input_options: Default::default(),
+            },
+        ));
+
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        conn.write_all(
+            b"type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0\ndeposit, 1, 1, 1.0\n",
+        )
+        .await
+        .unwrap();
+        conn.shutdown().await.unwrap();
+        let mut acks = vec![];
+        conn.read_to_end(&mut acks).await.unwrap();
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let mut reader = csv_async::AsyncDeserializer::from_reader(std::io::Cursor::new(acks));
+        let acks = reader
+            .deserialize::<CsvAckRecord>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            acks,
+            vec![
+                CsvAckRecord::new(0, "applied", ""),
+                CsvAckRecord::new(1, "rejected", "Insufficient funds for withdrawal"),
+                CsvAckRecord::new(
+                    2,
+                    "rejected",
+                    "Transaction with ID '1' has already been seen"
+                ),
+            ]
+        );
+    }
+
+    /// An idle client can't hold up shutdown once the drain is aborted, and the rows it sent still stand.
+    #[tokio::test]
+    async fn test_serve_abort_drops_idle_connections() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let (abort_tx, abort_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(crate::server::serve(
+            listener,
+            engine.sender(),
+            async {
+                shutdown_rx.await.ok();
+            },
+            async {
+                abort_rx.await.ok();
+            },
+            crate::server::ConnectionOptions {
+                verbose: false,
+                acknowledge: true,
+                input_format: crate::jsonl::InputFormat::Csv,
+                input_options: Default::default(),
+            },
+        ));
+
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n")
+            .await
+            .unwrap();
+        // Wait for the header and the deposit's ack, then leave the connection open:
+        let mut acks = tokio::io::BufReader::new(&mut conn).lines();
+        acks.next_line().await.unwrap().unwrap();
+        acks.next_line().await.unwrap().unwrap();
+        shutdown_tx.send(()).unwrap();
+        abort_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let client = engine_state.all_clients_state().get(1).unwrap();
+        assert_eq!(client.accounts(&Default::default()).available, 1.into());
+    }
+
+    /// A run that died halfway through its input, restarted with the full input and the same event log,
+    /// ends in the same state and reports the same rejections as a run that never died.
+    #[tokio::test]
+    async fn test_event_log_resume_after_crash() {
+        let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases")
+            .join("operations_on_chargedback_tx_ignored");
+        let full_input = std::fs::read_to_string(test_case_dir.join("input.csv")).unwrap();
+        let event_log = tempfile::NamedTempFile::new().unwrap();
+
+        // The first run dies before the last row, and the log's last entry is torn mid-write:
+        let partial_input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            partial_input.path(),
+            full_input
+                .lines()
+                .take(full_input.lines().count() - 1)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+        .unwrap();
+        main_inner(
+            &Args {
+                inputs: vec![partial_input.path().to_path_buf()],
+                event_log: Some(event_log.path().to_path_buf()),
+                ..Default::default()
+            },
+            &mut vec![],
+        )
+        .await
+        .unwrap();
+        let mut log_file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(event_log.path())
+            .unwrap();
+        std::io::Write::write_all(&mut log_file, &[200, 0, 0, 0, 1, 2]).unwrap();
+
+        // Events from the first run must not be applied twice:
+        let rejections_file = tempfile::NamedTempFile::new().unwrap();
+        let mut buf = vec![];
+        main_inner(
+            &Args {
+                inputs: vec![test_case_dir.join("input.csv")],
+                event_log: Some(event_log.path().to_path_buf()),
+                rejections: Some(rejections_file.path().to_path_buf()),
+                ..Default::default()
+            },
+            &mut buf,
+        )
+        .await
+        .unwrap();
+
+        let mut rejection_records =
+            rejections_csv_to_records(tokio::fs::File::open(rejections_file.path()).await.unwrap())
+                .await;
+        let expected_rejection_records = rejections_csv_to_records(
+            tokio::fs::File::open(test_case_dir.join("expected_rejections.csv"))
+                .await
+                .unwrap(),
+        )
+        .await;
+        for record in &mut rejection_records {
+            assert_eq!(
+                record.take_source(),
+                test_case_dir.join("input.csv").display().to_string()
+            );
+        }
+        assert_eq!(rejection_records, expected_rejection_records);
+
+        let output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
+        let expected_output_records = output_csv_to_records(
+            tokio::fs::File::open(test_case_dir.join("expected.csv"))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(output_records, expected_output_records);
+    }
+
+    /// A final log entry that is all there but fails its checksum is corruption, not a torn write to discard.
+    #[tokio::test]
+    async fn test_event_log_complete_last_entry_with_bad_checksum_is_corrupt() {
+        let event_log = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(event_log.path(), [2, 0, 0, 0, 0, 0, 0, 0, b'{', b'}']).unwrap();
+
+        assert!(
+            crate::event_log::EventLog::open(event_log.path())
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::metadata(event_log.path()).unwrap().len(), 10);
+    }
+
+    /// Processing an input in two batches, carrying state across with a snapshot,
+    /// ends in the same state as processing it in one go. The dispute in the first batch is resolved in the second.
+    #[tokio::test]
+    async fn test_snapshot_incremental_batches() {
+        let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases")
+            .join("multiple_clients_complex");
+        let full_input = std::fs::read_to_string(test_case_dir.join("input.csv")).unwrap();
+        let mut lines = full_input.lines();
+        let header = lines.next().unwrap();
+        let rows = lines.collect::<Vec<_>>();
+        let (first_rows, second_rows) = rows.split_at(rows.len() - 2);
+
+        let first_batch = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            first_batch.path(),
+            format!("{header}\n{}", first_rows.join("\n")),
+        )
+        .unwrap();
+        let second_batch = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            second_batch.path(),
+            format!("{header}\n{}", second_rows.join("\n")),
+        )
+        .unwrap();
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let first_snapshot = snapshot_dir.path().join("first.json");
+        let second_snapshot = snapshot_dir.path().join("second.json");
+
+        main_inner(
+            &Args {
+                inputs: vec![first_batch.path().to_path_buf()],
+                snapshot_out: Some(first_snapshot.clone()),
+                snapshot_every: Some(2.try_into().unwrap()),
+                ..Default::default()
+            },
+            &mut vec![],
+        )
+        .await
+        .unwrap();
+
+        let mut buf = vec![];
+        main_inner(
+            &Args {
+                inputs: vec![second_batch.path().to_path_buf()],
+                from_snapshot: Some(first_snapshot),
+                snapshot_out: Some(second_snapshot.clone()),
+                ..Default::default()
+            },
+            &mut buf,
+        )
+        .await
+        .unwrap();
+
+        let output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
+        let expected_output_records = output_csv_to_records(
+            tokio::fs::File::open(test_case_dir.join("expected.csv"))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(output_records, expected_output_records);
+
+        // The final snapshot carries everything, so restoring it alone reproduces the output:
+        let mut restored_buf = vec![];
+        let empty_batch = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(empty_batch.path(), header).unwrap();
+        main_inner(
+            &Args {
+                inputs: vec![empty_batch.path().to_path_buf()],
+                from_snapshot: Some(second_snapshot),
+                ..Default::default()
+            },
+            &mut restored_buf,
+        )
+        .await
+        .unwrap();
+        let restored_records = output_csv_to_records(std::io::Cursor::new(restored_buf)).await;
+        assert_eq!(restored_records, expected_output_records);
+    }
+
+    /// A periodic snapshot is written once per `--snapshot-every` events, not again for every query or tick
+    /// that leaves the count on a multiple.
+    #[tokio::test]
+    async fn test_periodic_snapshot_not_rewritten_without_events() {
+        use crate::engine::EngineQuery;
+
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let snapshot_path = snapshot_dir.path().join("snapshot.json");
+        let engine = engine::spawn_engine(engine::EngineConfig {
+            snapshot_every: Some((snapshot_path.clone(), 2.try_into().unwrap())),
+            ..Default::default()
+        });
+        let sender = engine.sender();
+        for txid in 1..=2 {
+            sender
+                .send_event_with_reply(deposit(txid, 1, 1.into(), None))
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+        // Answered only once the snapshot after the second deposit is written:
+        sender.query(EngineQuery::AllClients).await.unwrap();
+        assert!(snapshot_path.exists());
+
+        std::fs::remove_file(&snapshot_path).unwrap();
+        sender.query(EngineQuery::AllClients).await.unwrap();
+        sender
+            .send_event_with_reply(EngineEvent::Tick { timestamp: 0 })
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        sender.query(EngineQuery::AllClients).await.unwrap();
+        assert!(!snapshot_path.exists());
+        engine.shutdown().await.unwrap();
+    }
+
+    /// Cases needing admin operations between rows, run as two batches as admin operations follow the input.
+    /// The second batch's admin operations are optional.
+    /// - The rest of a partially charged back dispute stays open, so once the client is unlocked it can be settled,
+    ///   leaving nothing held and the client free to close.
+    /// - A transfer can't be charged back onto a sender closed since, which must hold nothing, but can be resolved.
+    #[rstest]
+    #[case::partial_chargeback_settled_after_unlock("partial_chargeback_settled_after_unlock")]
+    #[case::transfer_chargeback_to_closed_sender("transfer_chargeback_to_closed_sender")]
+    #[tokio::test]
+    async fn test_admin_between_batches(#[case] test_case_name: &str) {
+        let test_case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_cases")
+            .join(test_case_name);
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let snapshot_path = snapshot_dir.path().join("first.json");
+        let rejections_file = tempfile::NamedTempFile::new().unwrap();
+
+        main_inner(
+            &Args {
+                inputs: vec![test_case_dir.join("first.csv")],
+                admin: Some(test_case_dir.join("first_admin.csv")),
+                admin_token: Some("secret".to_string()),
+                snapshot_out: Some(snapshot_path.clone()),
+                ..Default::default()
+            },
+            &mut vec![],
+        )
+        .await
+        .unwrap();
+
+        let mut buf = vec![];
+        main_inner(
+            &Args {
+                inputs: vec![test_case_dir.join("second.csv")],
+                admin: Some(test_case_dir.join("second_admin.csv")).filter(|path| path.exists()),
+                admin_token: Some("secret".to_string()),
+                from_snapshot: Some(snapshot_path),
+                rejections: Some(rejections_file.path().to_path_buf()),
+                lock_reasons: true,
+                ..Default::default()
+            },
+            &mut buf,
+        )
+        .await
+        .unwrap();
+
+        let output_records = output_csv_to_records(std::io::Cursor::new(buf)).await;
+        let expected_output_records = output_csv_to_records(
+            tokio::fs::File::open(test_case_dir.join("expected.csv"))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(output_records, expected_output_records);
+
+        let mut rejection_records =
+            rejections_csv_to_records(tokio::fs::File::open(rejections_file.path()).await.unwrap())
+                .await;
+        for record in &mut rejection_records {
+            record.take_source();
+        }
+        let expected_rejection_records = rejections_csv_to_records(
+            tokio::fs::File::open(test_case_dir.join("expected_rejections.csv"))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(rejection_records, expected_rejection_records);
+    }
+
+    /// A client balance changed without a matching ledger transfer, here by editing a snapshot,
+    /// fails the trial balance instead of producing output.
+    #[tokio::test]
+    async fn test_trial_balance_catches_unbalanced_books() {
+        let input_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_cases/ledger_journal/input.csv");
+        let snapshot_dir = tempfile::tempdir().unwrap();
+        let snapshot_path = snapshot_dir.path().join("snapshot.json");
+        main_inner(
+            &Args {
+                inputs: vec![input_path.clone()],
+                snapshot_out: Some(snapshot_path.clone()),
+                ..Default::default()
+            },
+            &mut vec![],
+        )
+        .await
+        .unwrap();
+
+        let mut snapshot: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&snapshot_path).unwrap()).unwrap();
+        snapshot["state"]["ledger"]["platform"][""]["clearing"] = "0".into();
+        std::fs::write(&snapshot_path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let empty_batch = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(empty_batch.path(), "type,client,tx,amount").unwrap();
+        let mut buf = vec![];
+        let result = main_inner(
+            &Args {
+                inputs: vec![empty_batch.path().to_path_buf()],
+                from_snapshot: Some(snapshot_path),
+                ..Default::default()
+            },
+            &mut buf,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(buf.is_empty());
+    }
+
+    /// Balances at the limit of the decimal type reject further increases rather than overflowing,
+    /// leaving the client and the transaction untouched.
+    #[tokio::test]
+    async fn test_balance_overflow_rejected() {
+        use crate::transaction::TransactionState;
+
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        let sender = engine.sender();
+        let reason_codes = reason_codes(
+            &sender,
+            [
+                deposit(1, 1, DecimalType::MAX, None),
+                deposit(2, 1, 1.into(), None),
+                EngineEvent::Withdrawal {
+                    txid: 3,
+                    client_id: 1,
+                    amount: DecimalType::MAX,
+                    currency: Default::default(),
+                    timestamp: None,
+                },
+                deposit(4, 1, DecimalType::MAX, None),
+                EngineEvent::Dispute {
+                    txid: 1,
+                    client_id: 1,
+                    amount: None,
+                    timestamp: None,
+                },
+                // Held would overflow:
+                EngineEvent::Dispute {
+                    txid: 4,
+                    client_id: 1,
+                    amount: None,
+                    timestamp: None,
+                },
+                // Only total would overflow:
+                deposit(5, 1, 1.into(), None),
+            ],
+        )
+        .await;
+        assert_eq!(
+            reason_codes,
+            [
+                None,
+                Some("balance_overflow"),
+                None,
+                None,
+                None,
+                Some("balance_overflow"),
+                Some("balance_overflow"),
+            ]
+        );
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let client = engine_state.all_clients_state().get(1).unwrap();
+        assert_eq!(
+            (
+                client.accounts(&Default::default()).available,
+                client.accounts(&Default::default()).held,
+                client.accounts(&Default::default()).total()
+            ),
+            (0.into(), DecimalType::MAX, DecimalType::MAX)
+        );
+        assert_eq!(
+            client.transaction(4).unwrap().state(),
+            TransactionState::Normal
+        );
+    }
+
+    /// Balances can be queried mid-stream, without stopping ingestion, including over the query server.
+    #[tokio::test]
+    async fn test_live_queries() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        use crate::engine::{EngineQuery, EngineResponse};
+
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        let sender = engine.sender();
+        for event in [
+            deposit(1, 2, 10.into(), None),
+            deposit(2, 1, 5.into(), None),
+            EngineEvent::Dispute {
+                txid: 2,
+                client_id: 1,
+                amount: None,
+                timestamp: None,
+            },
+            EngineEvent::Deposit {
+                txid: 3,
+                client_id: 2,
+                amount: 1.into(),
+                currency: crate::ledger::Currency::new("btc"),
+                timestamp: None,
+            },
+        ] {
+            sender.send_event(event).await.unwrap();
+        }
+
+        let EngineResponse::ClientBalance(Some(balance)) = sender
+            .query(EngineQuery::ClientBalance {
+                client_id: 1,
+                currency: Default::default(),
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected client 1's balance");
+        };
+        assert_eq!(
+            (balance.available, balance.held, balance.locked),
+            (0.into(), 5.into(), false)
+        );
+        assert!(matches!(
+            sender
+                .query(EngineQuery::ClientBalance {
+                    client_id: 3,
+                    currency: Default::default(),
+                })
+                .await
+                .unwrap(),
+            EngineResponse::ClientBalance(None)
+        ));
+        let EngineResponse::Transaction(Some(summary)) = sender
+            .query(EngineQuery::Transaction {
+                client_id: 1,
+                txid: 2,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected tx 2's summary");
+        };
+        assert_eq!(
+            summary.state,
+            crate::transaction::TransactionState::Disputed
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let query_server = tokio::spawn(crate::server::serve_queries(listener, engine.sender()));
+        let (read_half, mut write_half) = tokio::net::TcpStream::connect(addr)
+            .await
+            .unwrap()
+            .into_split();
+        write_half
+            .write_all(b"clients\nbalance 2 btc\ntx 2 1\nbalance x\n")
+            .await
+            .unwrap();
+        let mut responses = tokio::io::BufReader::new(read_half).lines();
+        assert_eq!(
+            responses.next_line().await.unwrap().unwrap(),
+            r#"[{"client":1,"available":"0","held":"5","total":"5","locked":false},{"client":2,"available":"10","held":"0","total":"10","locked":false},{"client":2,"currency":"BTC","available":"1","held":"0","total":"1","locked":false}]"#
+        );
+        assert_eq!(
+            responses.next_line().await.unwrap().unwrap(),
+            r#"{"client":2,"currency":"BTC","available":"1","held":"0","total":"1","locked":false}"#
+        );
+        assert_eq!(
+            responses.next_line().await.unwrap().unwrap(),
+            r#"{"client":2,"tx":1,"type":"deposit","amount":"10","disputed":"0","state":"Normal"}"#
+        );
+        assert!(
+            responses
+                .next_line()
+                .await
+                .unwrap()
+                .unwrap()
+                .starts_with(r#"{"error":"Invalid id 'x'"#)
+        );
+        query_server.abort();
+
+        // Queries never affect the state returned at shutdown:
+        let engine_state = engine.shutdown().await.unwrap();
+        assert_eq!(engine_state.all_clients_state().iter().count(), 2);
+    }
+
+    /// Admin connections must authenticate before any operation is applied, and get each row's outcome back.
+    #[tokio::test]
+    async fn test_serve_admin() {
+        use tokio::io::AsyncWriteExt;
+
+        let engine = engine::spawn_engine(engine::EngineConfig::default());
+        engine
+            .sender()
+            .send_event(deposit(1, 1, 10.into(), None))
+            .await
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let admin_server = tokio::spawn(crate::server::serve_admin(
+            listener,
+            engine.sender(),
+            "secret".to_string(),
+        ));
+        let admin_request = async |request: &str| {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = vec![];
+            tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut response)
+                .await
+                .unwrap();
+            response
+        };
+
+        // Wrong token: connection dropped without reading any operations:
+        assert!(admin_request("guess\n").await.is_empty());
+        let mut reader = csv_async::AsyncDeserializer::from_reader(std::io::Cursor::new(
+            admin_request(
+                "secret\ntype,client,operator,reason\nfreeze,1,alice,suspected fraud\nunlock,2,alice,typo\n",
+            )
+            .await,
+        ));
+        let acks = reader
+            .deserialize::<CsvAckRecord>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            acks,
+            vec![
+                CsvAckRecord::new(0, "applied", ""),
+                CsvAckRecord::new(
+                    1,
+                    "rejected",
+                    "Client with ID '2' not found during an operation that requires an existing client"
+                ),
+            ]
+        );
+        admin_server.abort();
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let client = engine_state.all_clients_state().get(1).unwrap();
+        assert_eq!(
+            client.lock_reason().unwrap().to_string(),
+            "frozen by alice: suspected fraud"
+        );
+    }
+
+    /// A dispute the dispute policy rejects leaves the transaction as it was, so it doesn't count as an open dispute
+    /// for the risk rules or the output details, nor later expire.
+    #[tokio::test]
+    async fn test_rejected_dispute_leaves_transaction_unchanged() {
+        use crate::{
+            client::{AccountPolicy, DisputeDeadlines},
+            transaction::TransactionState,
+        };
+
+        let engine = engine::spawn_engine(engine::EngineConfig {
+            policy: AccountPolicy {
+                deadlines: DisputeDeadlines::from_days(None, Some(1)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let sender = engine.sender();
+        let reason_codes = reason_codes(
+            &sender,
+            [
+                deposit(1, 1, 10.into(), Some(0)),
+                EngineEvent::Withdrawal {
+                    txid: 2,
+                    client_id: 1,
+                    amount: 4.into(),
+                    currency: Default::default(),
+                    timestamp: Some(0),
+                },
+                EngineEvent::Dispute {
+                    txid: 2,
+                    client_id: 1,
+                    amount: None,
+                    timestamp: Some(0),
+                },
+                EngineEvent::Resolve {
+                    txid: 2,
+                    client_id: 1,
+                    amount: None,
+                    timestamp: Some(0),
+                },
+            ],
+        )
+        .await;
+        assert_eq!(
+            reason_codes,
+            [
+                None,
+                None,
+                Some("tx_cannot_be_disputed"),
+                Some("tx_cannot_be_disputed"),
+            ]
+        );
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let client = engine_state.all_clients_state().get(1).unwrap();
+        let withdrawal = client.transaction(2).unwrap();
+        assert_eq!(withdrawal.state(), TransactionState::Normal);
+        assert_eq!(withdrawal.disputed(), DecimalType::ZERO);
+        assert_eq!(withdrawal.disputed_at(), None);
+        assert_eq!(client.open_dispute_count(), 0);
+    }
+
+    /// A transfer's chargeback is rejected before either side changes once its sender is closed, in either engine,
+    /// where the router checks the sender's shard before forwarding the step to the receiver's.
+    #[rstest]
+    #[case::single(None)]
+    #[case::sharded(Some(3))]
+    #[tokio::test]
+    async fn test_transfer_chargeback_to_closed_sender(#[case] shards: Option<usize>) {
+        use crate::client::{AdminAction, AdminAudit};
+
+        let engine = spawn_engine(engine::EngineConfig::default(), shards);
+        let sender = engine.sender();
+        let reason_codes = reason_codes(
+            &sender,
+            [
+                deposit(1, 1, 10.into(), None),
+                EngineEvent::Transfer {
+                    txid: 2,
+                    from: 1,
+                    to: 2,
+                    amount: 10.into(),
+                    currency: Default::default(),
+                    timestamp: None,
+                },
+                EngineEvent::Dispute {
+                    txid: 2,
+                    client_id: 2,
+                    amount: None,
+                    timestamp: None,
+                },
+                EngineEvent::Admin {
+                    client_id: 1,
+                    action: AdminAction::Close,
+                    audit: AdminAudit {
+                        operator: "bob".to_string(),
+                        reason: "customer request".to_string(),
+                    },
+                    timestamp: None,
+                },
+                EngineEvent::Chargeback {
+                    txid: 2,
+                    client_id: 2,
+                    amount: None,
+                    timestamp: None,
+                },
+            ],
+        )
+        .await;
+        assert_eq!(
+            reason_codes,
+            [None, None, None, None, Some("client_closed")]
+        );
+
+        let engine_state = engine.shutdown().await.unwrap();
+        let receiver = engine_state.all_clients_state().get(2).unwrap();
+        assert_eq!(receiver.transaction(2).unwrap().disputed(), 10.into());
+        assert!(!receiver.locked());
+        let closed = engine_state.all_clients_state().get(1).unwrap();
+        assert_eq!(closed.accounts(&Default::default()).available, 0.into());
+    }
+
+    /// A lock is dated by the chargeback applying it, not by when the engine happened to run, in either engine.
+    #[rstest]
+    #[case::single(None)]
+    #[case::sharded(Some(3))]
+    #[tokio::test]
+    async fn test_locked_at_is_event_time(#[case] shards: Option<usize>) {
+        let engine = spawn_engine(engine::EngineConfig::default(), shards);
+        let sender = engine.sender();
+        for event in [
+            deposit(1, 1, 1.into(), Some(1_700_000_000)),
+            EngineEvent::Dispute {
+                txid: 1,
+                client_id: 1,
+                amount: None,
+                timestamp: Some(1_700_000_001),
+            },
+            EngineEvent::Chargeback {
+                txid: 1,
+                client_id: 1,
+                amount: None,
+                timestamp: Some(1_700_000_002),
+            },
+        ] {
+            sender.send_event(event).await.unwrap();
+        }
+        let engine_state = engine.shutdown().await.unwrap();
+        let client = engine_state.all_clients_state().get(1).unwrap();
+        assert_eq!(client.locked_at(), Some(1_700_000_002));
+    }
+
+    /// A sharded engine answers queries across all shards as if it were one engine.
+    #[tokio::test]
+    async fn test_sharded_queries() {
+        use crate::engine::{EngineQuery, EngineResponse};
+
+        let engine = crate::sharded_engine::spawn_sharded_engine(
+            engine::EngineConfig::default(),
+            4.try_into().unwrap(),
+        );
+        let sender = engine.sender();
+        for client_id in [7, 1, 2] {
+            sender
+                .send_event(deposit(client_id.into(), client_id, client_id.into(), None))
+                .await
+                .unwrap();
+        }
+        // A txid seen in another shard is still a duplicate:
+        let outcome = sender
+            .send_event_with_reply(deposit(7, 2, 1.into(), None))
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            engine::EventOutcome::Rejected(report)
+                if matches!(report.current_context(), crate::engine_error::EngineError::TxAlreadySeen(7))
+        ));
+
+        let EngineResponse::AllClients(balances) =
+            sender.query(EngineQuery::AllClients).await.unwrap()
+        else {
+            panic!("expected all clients' balances");
+        };
+        assert_eq!(
+            balances
+                .iter()
+                .map(|balance| (balance.client_id, balance.available))
+                .collect::<Vec<_>>(),
+            vec![(1, 1.into()), (2, 2.into()), (7, 7.into())]
+        );
+
+        let engine_state = engine.shutdown().await.unwrap();
+        assert_eq!(engine_state.all_clients_state().iter().count(), 3);
     }
 }
@@ -0,0 +1,243 @@
+use std::str::FromStr;
+
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    DecimalType,
+    app_error::AppError,
+    client::{AllClientsState, ClientId, ClientState},
+    csv,
+    ledger::{ClientAccounts, Currency},
+    serialize_decimal,
+};
+
+/// Format of the final client states.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// The brief's `client, available, held, total, locked` csv.
+    #[default]
+    Csv,
+    /// A single json array of client objects.
+    Json,
+    /// One json client object per line.
+    Jsonl,
+}
+
+/// Order of the final client rows.
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+pub enum OutputSort {
+    /// Ascending client id.
+    #[default]
+    Client,
+    /// Descending total balance, ties by client id.
+    Total,
+    /// Locked clients first, each group by client id.
+    LockedFirst,
+}
+
+/// An inclusive range of client ids, given as `START-END`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientRange {
+    start: ClientId,
+    end: ClientId,
+}
+
+impl ClientRange {
+    fn contains(&self, client_id: ClientId) -> bool {
+        (self.start..=self.end).contains(&client_id)
+    }
+}
+
+impl FromStr for ClientRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected START-END, got '{s}'"))?;
+        let parse = |id: &str| {
+            id.trim()
+                .parse::<ClientId>()
+                .map_err(|e| format!("invalid client id '{id}': {e}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("range start {start} is after its end {end}"));
+        }
+        Ok(ClientRange { start, end })
+    }
+}
+
+/// Which rows the final output includes, every client in every asset by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OutputFilter {
+    pub only_locked: bool,
+    /// Only balances with funds held by a dispute.
+    pub only_held: bool,
+    pub client_range: Option<ClientRange>,
+}
+
+impl OutputFilter {
+    fn includes(&self, row: &OutputRow) -> bool {
+        (!self.only_locked || row.client.locked())
+            && (!self.only_held || row.accounts.held != DecimalType::ZERO)
+            && self
+                .client_range
+                .is_none_or(|range| range.contains(row.client_id))
+    }
+}
+
+/// How the final client states are written.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    pub sort: OutputSort,
+    pub filter: OutputFilter,
+    /// Write why each client is locked, in the `locked` csv column or an extra `lock_reason` json field.
+    pub lock_reasons: bool,
+    /// Add the json-only `transactions`, `open_disputes` and `locked_at` fields.
+    pub details: bool,
+}
+
+/// One row of the final output, a client's balances in one asset.
+pub struct OutputRow<'a> {
+    pub client_id: ClientId,
+    pub client: &'a ClientState,
+    pub currency: &'a Currency,
+    pub accounts: ClientAccounts,
+}
+
+/// A client in the json formats, the csv columns plus anything the csv can't carry well.
+#[derive(Serialize)]
+struct JsonOutputRecord {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(serialize_with = "serialize_decimal")]
+    available: DecimalType,
+    #[serde(serialize_with = "serialize_decimal")]
+    held: DecimalType,
+    #[serde(serialize_with = "serialize_decimal")]
+    total: DecimalType,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock_reason: Option<String>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "crate::serialize_optional_decimal"
+    )]
+    fees: Option<DecimalType>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    details: Option<OutputDetails>,
+}
+
+#[derive(Serialize)]
+struct OutputDetails {
+    transactions: usize,
+    open_disputes: usize,
+    /// Seconds since the unix epoch, `null` when not locked.
+    locked_at: Option<u64>,
+}
+
+/// Write the selected clients in the requested order and format.
+pub async fn output_client_state(
+    all_clients_state: &AllClientsState,
+    mut writer: impl AsyncWrite + Unpin,
+    options: &OutputOptions,
+) -> Result<(), Report<AppError>> {
+    if options.format == OutputFormat::Csv {
+        return csv::output_client_state(all_clients_state, writer, options).await;
+    }
+
+    let show_currency = has_named_currencies(all_clients_state);
+    let show_fees = has_fees(all_clients_state);
+    let records = select_rows(all_clients_state, options)
+        .into_iter()
+        .map(|row| JsonOutputRecord {
+            client: row.client_id,
+            currency: show_currency.then(|| row.currency.clone()),
+            available: row.accounts.available,
+            held: row.accounts.held,
+            total: row.accounts.total(),
+            locked: row.client.locked(),
+            lock_reason: options
+                .lock_reasons
+                .then(|| row.client.lock_reason().map(ToString::to_string))
+                .flatten(),
+            fees: show_fees.then(|| row.client.fees_paid(row.currency)),
+            details: options.details.then(|| OutputDetails {
+                transactions: row.client.transaction_count(),
+                open_disputes: row.client.open_dispute_count(),
+                locked_at: row.client.locked_at(),
+            }),
+        });
+
+    match options.format {
+        OutputFormat::Csv => unreachable!("handled above"),
+        OutputFormat::Json => {
+            let json =
+                serde_json::to_vec_pretty(&records.collect::<Vec<_>>()).change_context(AppError)?;
+            writer.write_all(&json).await.change_context(AppError)?;
+        }
+        OutputFormat::Jsonl => {
+            for record in records {
+                let mut line = serde_json::to_vec(&record).change_context(AppError)?;
+                line.push(b'\n');
+                writer.write_all(&line).await.change_context(AppError)?;
+            }
+        }
+    }
+    writer.flush().await.change_context(AppError)?;
+    Ok(())
+}
+
+/// Whether any client holds an asset other than the default, in which case every output row names its currency.
+/// Otherwise the output keeps the brief's exact columns.
+pub fn has_named_currencies(all_clients_state: &AllClientsState) -> bool {
+    all_clients_state.iter().any(|(_, client)| {
+        client
+            .balances()
+            .any(|(currency, _)| !currency.is_default())
+    })
+}
+
+/// Whether any client was ever charged a fee, in which case every output row has the fees it paid.
+pub fn has_fees(all_clients_state: &AllClientsState) -> bool {
+    all_clients_state
+        .iter()
+        .any(|(_, client)| client.has_paid_fees())
+}
+
+/// The rows to output, one per client per asset, in order.
+/// The state is a `HashMap`, so every order ends with the client id then currency to be the same on every run.
+pub fn select_rows<'a>(
+    all_clients_state: &'a AllClientsState,
+    options: &OutputOptions,
+) -> Vec<OutputRow<'a>> {
+    let mut rows = all_clients_state
+        .iter()
+        .flat_map(|(client_id, client)| {
+            client.balances().map(|(currency, accounts)| OutputRow {
+                client_id: *client_id,
+                client,
+                currency,
+                accounts,
+            })
+        })
+        .filter(|row| options.filter.includes(row))
+        .collect::<Vec<_>>();
+    // Each client's rows are already in currency order, and every sort is stable:
+    match options.sort {
+        OutputSort::Client => rows.sort_by_key(|row| row.client_id),
+        OutputSort::Total => rows.sort_by(|a, b| {
+            b.accounts
+                .total()
+                .cmp(&a.accounts.total())
+                .then(a.client_id.cmp(&b.client_id))
+        }),
+        OutputSort::LockedFirst => rows.sort_by_key(|row| (!row.client.locked(), row.client_id)),
+    }
+    rows
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::VecDeque,
+    num::{NonZeroU64, NonZeroUsize},
+    path::Path,
+};
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    DecimalType,
+    app_error::AppError,
+    client::ClientState,
+    engine_error::EngineError,
+    ledger::Currency,
+    transaction::{Timestamp, Transaction},
+};
+
+/// Risk rules checked before a withdrawal is applied.
+/// Every rule is optional, and applies per asset where it names an amount.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskRules {
+    /// Largest amount a single withdrawal may be for.
+    #[serde(default)]
+    max_withdrawal: Option<DecimalType>,
+    /// Largest total a client may withdraw across its most recent transactions, or its most recent seconds.
+    #[serde(default)]
+    velocity: Option<VelocityRule>,
+    /// Most disputes a client may have open and still withdraw.
+    #[serde(default)]
+    max_open_disputes: Option<usize>,
+    /// Available funds a withdrawal must leave behind, after its fee.
+    #[serde(default)]
+    min_reserve: Option<DecimalType>,
+}
+
+/// Exactly one of `client_transactions` and `seconds` sets the window.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VelocityRule {
+    max_total: DecimalType,
+    /// How many of the client's own last transactions the total is over, the withdrawal being checked included.
+    /// Other clients' transactions don't count, so the window is the same however the engine is sharded.
+    #[serde(default)]
+    client_transactions: Option<NonZeroUsize>,
+    /// How many seconds before the withdrawal being checked the total is over, by the time of each withdrawal.
+    #[serde(default)]
+    seconds: Option<NonZeroU64>,
+}
+
+/// Read the rules from a json file, e.g. `{"max_withdrawal": "1000", "velocity": {"max_total": "2500",
+/// "client_transactions": 10}, "max_open_disputes": 2, "min_reserve": "50"}`, or `"seconds": 86400` for a daily velocity.
+pub async fn read_risk_rules(path: &Path) -> Result<RiskRules, Report<AppError>> {
+    let contents = tokio::fs::read(path)
+        .await
+        .change_context(AppError)
+        .attach_with(|| format!("Reading risk rules {path:?}"))?;
+    let rules: RiskRules = serde_json::from_slice(&contents)
+        .change_context(AppError)
+        .attach_with(|| format!("Parsing risk rules {path:?}"))?;
+    if let Some(velocity) = rules.velocity {
+        if velocity.client_transactions.is_some() == velocity.seconds.is_some() {
+            return Err(Report::new(AppError).attach(format!(
+                "Risk rules {path:?} must give a velocity window of either client_transactions or seconds"
+            )));
+        }
+    }
+    let amounts = [
+        rules.max_withdrawal,
+        rules.velocity.map(|velocity| velocity.max_total),
+        rules.min_reserve,
+    ];
+    if amounts
+        .into_iter()
+        .flatten()
+        .any(|amount| amount < DecimalType::ZERO)
+    {
+        return Err(
+            Report::new(AppError).attach(format!("Risk rules {path:?} have a negative amount"))
+        );
+    }
+    Ok(rules)
+}
+
+impl RiskRules {
+    /// Check `client` may have `tx` and its `fee` taken out of its available funds.
+    pub fn check_withdrawal(
+        &self,
+        client: &ClientState,
+        tx: &Transaction,
+        fee: DecimalType,
+    ) -> Result<(), Report<EngineError>> {
+        let txid = tx.txid();
+        if let Some(limit) = self.max_withdrawal {
+            if tx.amount() > limit {
+                return Err(Report::from(EngineError::RiskMaxWithdrawal(txid))
+                    .attach(format!("Amount {} exceeds limit {limit}", tx.amount())));
+            }
+        }
+        if let Some(velocity) = self.velocity {
+            let window = Window::ending_at(
+                velocity,
+                client.transaction_count(),
+                tx.timestamp().unwrap_or_default(),
+            );
+            let total = client
+                .recent_withdrawals()
+                .total_within(window, tx.currency())
+                .and_then(|total| total.checked_add(tx.amount()))
+                .ok_or(EngineError::BalanceOverflow(txid))?;
+            if total > velocity.max_total {
+                let over = match (velocity.client_transactions, velocity.seconds) {
+                    (Some(transactions), _) => format!("{transactions} client transactions"),
+                    (_, seconds) => format!("{} seconds", seconds.map_or(0, NonZeroU64::get)),
+                };
+                return Err(
+                    Report::from(EngineError::RiskVelocity(txid)).attach(format!(
+                        "Withdrawals of {total} over the last {over} exceed limit {}",
+                        velocity.max_total
+                    )),
+                );
+            }
+        }
+        if let Some(limit) = self.max_open_disputes {
+            let open = client.open_dispute_count();
+            if open > limit {
+                return Err(Report::from(EngineError::RiskOpenDisputes(txid))
+                    .attach(format!("{open} open disputes exceed limit {limit}")));
+            }
+        }
+        if let Some(reserve) = self.min_reserve {
+            let left = client
+                .accounts(tx.currency())
+                .available
+                .checked_sub(tx.amount())
+                .and_then(|left| left.checked_sub(fee))
+                .ok_or(EngineError::BalanceOverflow(txid))?;
+            if left < reserve {
+                return Err(
+                    Report::from(EngineError::RiskMinReserve(txid)).attach(format!(
+                        "Available funds {left} would be under reserve {reserve}"
+                    )),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember a withdrawal applied as the client's transaction at `position`, if the velocity rule needs it.
+    pub fn record_withdrawal(
+        &self,
+        recent: &mut RecentWithdrawals,
+        position: usize,
+        tx: &Transaction,
+    ) {
+        if let Some(velocity) = self.velocity {
+            let withdrawal = RecentWithdrawal {
+                position,
+                currency: tx.currency().clone(),
+                amount: tx.amount(),
+                timestamp: tx.timestamp().unwrap_or_default(),
+            };
+            // Anything before this window is before every later one too:
+            let window = Window::ending_at(velocity, position, withdrawal.timestamp);
+            recent.0.push_back(withdrawal);
+            while recent
+                .0
+                .front()
+                .is_some_and(|first| !window.contains(first))
+            {
+                recent.0.pop_front();
+            }
+        }
+    }
+}
+
+/// The first of the client's transactions, or the first second, in a velocity window.
+#[derive(Debug, Clone, Copy)]
+enum Window {
+    FromPosition(usize),
+    FromTime(Timestamp),
+}
+
+impl Window {
+    /// The window ending with the client's transaction at `position`, made at `now`.
+    fn ending_at(velocity: VelocityRule, position: usize, now: Timestamp) -> Window {
+        match (velocity.client_transactions, velocity.seconds) {
+            (Some(transactions), _) => {
+                Window::FromPosition((position + 1).saturating_sub(transactions.get()))
+            }
+            (_, seconds) => Window::FromTime(
+                now.saturating_add(1)
+                    .saturating_sub(seconds.map_or(0, NonZeroU64::get)),
+            ),
+        }
+    }
+
+    fn contains(self, withdrawal: &RecentWithdrawal) -> bool {
+        match self {
+            Window::FromPosition(start) => withdrawal.position >= start,
+            Window::FromTime(start) => withdrawal.timestamp >= start,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecentWithdrawal {
+    /// Position among the client's transactions.
+    position: usize,
+    currency: Currency,
+    amount: DecimalType,
+    #[serde(default)]
+    timestamp: Timestamp,
+}
+
+/// A client's withdrawals within the velocity window.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RecentWithdrawals(VecDeque<RecentWithdrawal>);
+
+impl RecentWithdrawals {
+    /// Total withdrawn in `currency` within `window`, `None` on overflow.
+    fn total_within(&self, window: Window, currency: &Currency) -> Option<DecimalType> {
+        self.0
+            .iter()
+            .filter(|withdrawal| window.contains(withdrawal) && withdrawal.currency == *currency)
+            .try_fold(DecimalType::ZERO, |total, withdrawal| {
+                total.checked_add(withdrawal.amount)
+            })
+    }
+}
@@ -0,0 +1,256 @@
+use error_stack::{Report, ResultExt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+use crate::{
+    ACK_BUFFER_SIZE, admin,
+    app_error::AppError,
+    csv::{self, InputOptions},
+    engine::{EngineQuery, EngineResponse, EngineSender},
+    jsonl::InputFormat,
+    ledger::Currency,
+};
+
+/// How each connection to `serve` is read and answered.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    pub verbose: bool,
+    /// Write every row's outcome back to its connection as a csv row.
+    pub acknowledge: bool,
+    pub input_format: InputFormat,
+    /// Rows are checked as they arrive, so even strict validation can't stop earlier rows applying.
+    pub input_options: InputOptions,
+}
+
+/// Accept TCP connections until `shutdown` resolves, each connection streaming rows into the engine as `options` say.
+///
+/// Every connection gets its own clone of the engine sender, so all connections share the engine's
+/// bounded channel and therefore its backpressure.
+/// Once `shutdown` resolves no new connections are accepted,
+/// and this only returns after every open connection has been drained and closed by its client,
+/// or `abort` resolves during the drain, dropping the connections still open.
+/// A panicking connection is logged, like one that errors, and never stops the others.
+pub async fn serve(
+    listener: TcpListener,
+    sender: EngineSender,
+    shutdown: impl Future<Output = ()>,
+    abort: impl Future<Output = ()>,
+    options: ConnectionOptions,
+) -> Result<(), Report<AppError>> {
+    let mut shutdown = std::pin::pin!(shutdown);
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => {
+                let (stream, peer_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Usually transient (e.g. fd exhaustion, aborted handshake), keep serving:
+                        eprintln!("Failed to accept connection: {e}");
+                        continue;
+                    }
+                };
+                if options.verbose {
+                    eprintln!("Accepted connection from {peer_addr}");
+                }
+                let sender = sender.clone();
+                connections.spawn(async move {
+                    // A bad stream only drops its own connection, never the engine or other connections:
+                    if let Err(report) = handle_connection(stream, &peer_addr.to_string(), &sender, options).await {
+                        eprintln!("Connection from {peer_addr} aborted: {report:?}");
+                    }
+                });
+            }
+            // Reap finished connections as we go, so the set doesn't grow with every connection ever accepted:
+            Some(joined) = connections.join_next(), if !connections.is_empty() => log_panic(joined),
+        }
+    }
+
+    // Stop accepting before draining:
+    drop(listener);
+    let mut abort = std::pin::pin!(abort);
+    loop {
+        tokio::select! {
+            _ = &mut abort => {
+                eprintln!("Aborting {} open connection(s)", connections.len());
+                connections.shutdown().await;
+                break;
+            }
+            joined = connections.join_next() => match joined {
+                Some(joined) => log_panic(joined),
+                None => break,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn log_panic(joined: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = joined {
+        eprintln!("Connection task panicked: {e}");
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    source: &str,
+    sender: &EngineSender,
+    options: ConnectionOptions,
+) -> Result<(), Report<AppError>> {
+    let ConnectionOptions {
+        verbose,
+        acknowledge,
+        input_format,
+        input_options,
+    } = options;
+    if !acknowledge {
+        return input_format
+            .process_input(sender, stream, source, input_options, verbose, None)
+            .await;
+    }
+
+    let (read_half, write_half) = stream.into_split();
+    let (ack_tx, ack_rx) = tokio::sync::mpsc::channel(ACK_BUFFER_SIZE);
+    let ack_writer = tokio::spawn(csv::output_acks(ack_rx, write_half));
+    // Dropping the ack sender at the end of input lets the writer finish:
+    let input_result = input_format
+        .process_input(
+            sender,
+            read_half,
+            source,
+            input_options,
+            verbose,
+            Some(ack_tx),
+        )
+        .await;
+    let ack_result = ack_writer
+        .await
+        .change_context(AppError)
+        .attach("ack writer task panicked")?;
+    input_result.and(ack_result)
+}
+
+/// Apply admin operations from each connection until aborted, answering every row with its outcome.
+///
+/// Each connection must authenticate by sending `token` as its first line, see `admin::process_admin_input`.
+pub async fn serve_admin(listener: TcpListener, sender: EngineSender, token: String) {
+    let token = std::sync::Arc::<str>::from(token);
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept admin connection: {e}");
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                let token = token.clone();
+                connections.spawn(async move {
+                    let (read_half, write_half) = stream.into_split();
+                    if let Err(report) =
+                        admin::process_admin_input(&sender, read_half, &token, write_half).await
+                    {
+                        eprintln!("Admin connection from {peer_addr} aborted: {report:?}");
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+/// Answer read-only queries about the live engine state, one json line per request line, until aborted.
+///
+/// Requests are `balance <client> [currency]`, defaulting to the default asset, `clients` or `tx <client> <tx>`.
+/// Unknown clients and transactions are answered with `null`, malformed requests with `{"error": ...}`.
+pub async fn serve_queries(listener: TcpListener, sender: EngineSender) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept query connection: {e}");
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                connections.spawn(async move {
+                    if let Err(report) = handle_query_connection(stream, &sender).await {
+                        eprintln!("Query connection from {peer_addr} aborted: {report:?}");
+                    }
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+async fn handle_query_connection(
+    stream: TcpStream,
+    sender: &EngineSender,
+) -> Result<(), Report<AppError>> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read_half).lines();
+    while let Some(line) = lines.next_line().await.change_context(AppError)? {
+        let mut response = match parse_query(&line) {
+            Ok(query) => match sender.query(query).await? {
+                EngineResponse::ClientBalance(balance) => serde_json::to_vec(&balance),
+                EngineResponse::AllClients(balances) => serde_json::to_vec(&balances),
+                EngineResponse::Transaction(summary) => serde_json::to_vec(&summary),
+                EngineResponse::EngineState(_) => {
+                    return Err(Report::new(AppError)
+                        .attach("engine answered a query with a response of another kind"));
+                }
+            },
+            Err(error) => serde_json::to_vec(&serde_json::json!({ "error": error })),
+        }
+        .change_context(AppError)?;
+        response.push(b'\n');
+        write_half
+            .write_all(&response)
+            .await
+            .change_context(AppError)?;
+    }
+    Ok(())
+}
+
+fn parse_query(line: &str) -> Result<EngineQuery, String> {
+    let parts = line.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["balance", client_id] => Ok(EngineQuery::ClientBalance {
+            client_id: parse_id(line, client_id)?,
+            currency: Currency::default(),
+        }),
+        ["balance", client_id, currency] => Ok(EngineQuery::ClientBalance {
+            client_id: parse_id(line, client_id)?,
+            currency: Currency::new(currency),
+        }),
+        ["clients"] => Ok(EngineQuery::AllClients),
+        ["tx", client_id, txid] => Ok(EngineQuery::Transaction {
+            client_id: parse_id(line, client_id)?,
+            txid: parse_id(line, txid)?,
+        }),
+        _ => Err(format!(
+            "Unknown query '{line}', expected 'balance <client> [currency]', 'clients' or 'tx <client> <tx>'"
+        )),
+    }
+}
+
+fn parse_id<T: std::str::FromStr<Err: std::fmt::Display>>(
+    line: &str,
+    part: &str,
+) -> Result<T, String> {
+    part.parse()
+        .map_err(|e| format!("Invalid id '{part}' in query '{line}': {e}"))
+}
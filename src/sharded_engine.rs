@@ -0,0 +1,346 @@
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+};
+
+use error_stack::Report;
+
+use crate::{
+    DecimalType,
+    app_error::AppError,
+    client::{ClientId, DisputeStep},
+    engine::{
+        EngineConfig, EngineEvent, EngineHandle, EngineMessage, EngineQuery, EngineResponse,
+        EngineSender, EngineState, EventOutcome, TransferHalf, engine_channels, spawn_engine,
+    },
+    engine_error::EngineError,
+    ledger::Currency,
+    transaction::{Timestamp, TransactionId},
+};
+
+/// Spawn `shard_count` engines that each own the clients with `client_id % shard_count` equal to their index,
+/// behind a router with the same `EngineHandle` API as a single engine.
+///
+/// The router forwards each message to its client's shard in arrival order, so per-client ordering is kept.
+/// Global txid uniqueness is checked by the router before forwarding.
+/// Just like the single engine, a txid is used up even if the shard then rejects the transaction.
+///
+/// The other cross-client rule, that a transfer applies as a unit, is kept by the router applying a transfer
+/// between shards one side at a time: it checks the receiving side, applies the sending side, and only then
+/// the receiving side, waiting on each shard before forwarding anything else. Each dispute step of such a transfer
+/// is likewise mirrored onto the sending side's shard once the receiving side's shard has applied it.
+///
+/// Events are given their time by the router, like the single engine would, and the halves and mirrored steps
+/// carry it on. Before shutting down, every shard is brought up to the router's clock, so the disputes still open
+/// expire as of the same time in all of them.
+pub fn spawn_sharded_engine(config: EngineConfig, shard_count: NonZeroUsize) -> EngineHandle {
+    let (engine_handle, mut engine_event_rx, response_tx) = engine_channels();
+    let shards = (0..shard_count.get())
+        .map(|_| spawn_engine(config.shard_config()))
+        .collect::<Vec<_>>();
+    let shard_senders = shards.iter().map(EngineHandle::sender).collect::<Vec<_>>();
+
+    tokio::spawn(async move {
+        let mut seen_txids = HashSet::new();
+        let mut router_events = 0;
+        let mut clock = 0;
+        // The sending and receiving clients of each transfer between shards:
+        let mut split_transfers = HashMap::<TransactionId, (ClientId, ClientId)>::new();
+
+        loop {
+            // Every handle and sender is gone, so nobody is waiting on the final state:
+            let Some(message) = engine_event_rx.recv().await else {
+                return;
+            };
+            let forwarded = match message {
+                EngineMessage::Event {
+                    event: EngineEvent::Exit,
+                    ..
+                } => break,
+                EngineMessage::Event {
+                    mut event,
+                    reply_tx,
+                } => {
+                    event.stamp(clock);
+                    // Checked here against the global clock, as a shard's own can lag behind it:
+                    if let Err(report) = event.advance_clock(&mut clock) {
+                        router_events += 1;
+                        reply(reply_tx, EventOutcome::Rejected(report), config.verbose);
+                        continue;
+                    }
+                    if let Some(txid) = event.new_txid() {
+                        if !seen_txids.insert(txid) {
+                            router_events += 1;
+                            let report = Report::new(EngineError::TxAlreadySeen(txid));
+                            reply(reply_tx, EventOutcome::Rejected(report), config.verbose);
+                            continue;
+                        }
+                    }
+                    match event {
+                        EngineEvent::Transfer {
+                            txid,
+                            from,
+                            to,
+                            amount,
+                            currency,
+                            timestamp,
+                        } if !same_shard(&shard_senders, from, to) => {
+                            // Neither shard counts its half, as they make up the one event:
+                            router_events += 1;
+                            let outcome = transfer_across_shards(
+                                &shard_senders,
+                                txid,
+                                from,
+                                to,
+                                amount,
+                                currency,
+                                timestamp,
+                            )
+                            .await;
+                            if let EventOutcome::Applied = outcome {
+                                split_transfers.insert(txid, (from, to));
+                            }
+                            reply(reply_tx, outcome, config.verbose);
+                            Ok(())
+                        }
+                        event => match dispute_step(&event) {
+                            Some((txid, client_id, step, amount))
+                                if split_transfers
+                                    .get(&txid)
+                                    .is_some_and(|(_, to)| *to == client_id) =>
+                            {
+                                let timestamp = event.timestamp();
+                                let (from, _) = split_transfers[&txid];
+                                let check = TransferHalf::CheckStep { txid, step };
+                                let outcome =
+                                    match send_half(&shard_senders, from, check, timestamp).await {
+                                        EventOutcome::Applied => {
+                                            send_with_reply(&shard_senders, event).await
+                                        }
+                                        rejected => {
+                                            // Never reaching the receiver's shard to be counted:
+                                            router_events += 1;
+                                            rejected
+                                        }
+                                    };
+                                if let EventOutcome::Applied = outcome {
+                                    let mirror = TransferHalf::Step { txid, step, amount };
+                                    must_apply(
+                                        send_half(&shard_senders, from, mirror, timestamp).await,
+                                    );
+                                }
+                                reply(reply_tx, outcome, config.verbose);
+                                Ok(())
+                            }
+                            _ => {
+                                let client_id = event
+                                    .client_id()
+                                    .expect("only control events have no client");
+                                shard_for(&shard_senders, client_id)
+                                    .send_message(EngineMessage::Event { event, reply_tx })
+                                    .await
+                            }
+                        },
+                    }
+                }
+                EngineMessage::Query {
+                    query: EngineQuery::AllClients,
+                    response_tx,
+                } => {
+                    let mut all_balances = vec![];
+                    for shard in &shard_senders {
+                        match shard.query(EngineQuery::AllClients).await {
+                            Ok(EngineResponse::AllClients(balances)) => {
+                                all_balances.extend(balances)
+                            }
+                            Ok(_) => unreachable!("shards answer queries in kind"),
+                            Err(report) => exit_with(report),
+                        }
+                    }
+                    // Stable, so each client's balances stay in currency order:
+                    all_balances.sort_by_key(|balance| balance.client_id);
+                    let _ = response_tx.send(EngineResponse::AllClients(all_balances));
+                    Ok(())
+                }
+                EngineMessage::Query { query, response_tx } => {
+                    let client_id = match query {
+                        EngineQuery::ClientBalance { client_id, .. }
+                        | EngineQuery::Transaction { client_id, .. } => client_id,
+                        EngineQuery::AllClients => unreachable!("handled above"),
+                    };
+                    shard_for(&shard_senders, client_id)
+                        .send_message(EngineMessage::Query { query, response_tx })
+                        .await
+                }
+            };
+            if let Err(report) = forwarded {
+                exit_with(report);
+            }
+        }
+
+        for shard in &shard_senders {
+            if let Err(report) = shard
+                .send_event(EngineEvent::Tick { timestamp: clock })
+                .await
+            {
+                exit_with(report);
+            }
+        }
+        let mut shard_states = Vec::with_capacity(shards.len());
+        for shard in shards {
+            match shard.shutdown().await {
+                Ok(shard_state) => shard_states.push(shard_state),
+                Err(report) => exit_with(report),
+            }
+        }
+        let merged = match EngineState::merge_shards(shard_states, seen_txids, router_events) {
+            Ok(merged) => merged,
+            Err(report) => exit_with(report),
+        };
+        response_tx
+            .send(EngineResponse::EngineState(merged))
+            .await
+            .unwrap();
+    });
+
+    engine_handle
+}
+
+fn shard_for(shards: &[EngineSender], client_id: ClientId) -> &EngineSender {
+    &shards[client_id as usize % shards.len()]
+}
+
+fn same_shard(shards: &[EngineSender], a: ClientId, b: ClientId) -> bool {
+    a as usize % shards.len() == b as usize % shards.len()
+}
+
+/// The transaction, client, step and amount of a dispute, resolve or chargeback.
+fn dispute_step(
+    event: &EngineEvent,
+) -> Option<(TransactionId, ClientId, DisputeStep, Option<DecimalType>)> {
+    match *event {
+        EngineEvent::Dispute {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => Some((txid, client_id, DisputeStep::Dispute, amount)),
+        EngineEvent::Resolve {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => Some((txid, client_id, DisputeStep::Resolve, amount)),
+        EngineEvent::Chargeback {
+            txid,
+            client_id,
+            amount,
+            ..
+        } => Some((txid, client_id, DisputeStep::Chargeback, amount)),
+        _ => None,
+    }
+}
+
+/// Send `event` to its client's shard and wait for it to be applied or rejected,
+/// so nothing else reaches any shard in between.
+async fn send_with_reply(shards: &[EngineSender], event: EngineEvent) -> EventOutcome {
+    let client_id = event
+        .client_id()
+        .expect("only control events have no client");
+    let outcome = match shard_for(shards, client_id)
+        .send_event_with_reply(event)
+        .await
+    {
+        Ok(reply_rx) => reply_rx.await,
+        Err(report) => exit_with(report),
+    };
+    outcome
+        .unwrap_or_else(|_| exit_with(Report::new(AppError).attach("engine shutdown unexpectedly")))
+}
+
+/// Check the receiving side, then apply the sending side and only then the receiving side.
+async fn transfer_across_shards(
+    shards: &[EngineSender],
+    txid: TransactionId,
+    from: ClientId,
+    to: ClientId,
+    amount: DecimalType,
+    currency: Currency,
+    timestamp: Option<Timestamp>,
+) -> EventOutcome {
+    let check = TransferHalf::CheckReceive {
+        txid,
+        from,
+        amount,
+        currency: currency.clone(),
+    };
+    if let EventOutcome::Rejected(report) = send_half(shards, to, check, timestamp).await {
+        return EventOutcome::Rejected(report);
+    }
+    let send = TransferHalf::Send {
+        txid,
+        to,
+        amount,
+        currency: currency.clone(),
+    };
+    let outcome = send_half(shards, from, send, timestamp).await;
+    if let EventOutcome::Applied = outcome {
+        let receive = TransferHalf::Receive {
+            txid,
+            from,
+            amount,
+            currency,
+        };
+        must_apply(send_half(shards, to, receive, timestamp).await);
+    }
+    outcome
+}
+
+async fn send_half(
+    shards: &[EngineSender],
+    client_id: ClientId,
+    half: TransferHalf,
+    timestamp: Option<Timestamp>,
+) -> EventOutcome {
+    let half = EngineEvent::TransferHalf {
+        client_id,
+        half,
+        timestamp,
+    };
+    send_with_reply(shards, half).await
+}
+
+/// The half of a transfer that follows the first can't fail, or the transfer would no longer be a unit.
+fn must_apply(outcome: EventOutcome) {
+    if let EventOutcome::Rejected(report) = outcome {
+        exit_with(
+            report
+                .change_context(EngineError::InternalError)
+                .attach("Second half of a transfer between shards was rejected"),
+        );
+    }
+}
+
+/// Reply to the submitter if it asked, as a shard would, otherwise print rejections in verbose mode.
+fn reply(
+    reply_tx: Option<tokio::sync::oneshot::Sender<EventOutcome>>,
+    outcome: EventOutcome,
+    verbose: bool,
+) {
+    match (reply_tx, outcome) {
+        (Some(reply_tx), outcome) => {
+            // The submitter may have stopped waiting, that's their choice:
+            let _ = reply_tx.send(outcome);
+        }
+        (None, EventOutcome::Rejected(report)) if verbose => {
+            eprintln!("Engine rejected request: {:?}", report.current_context());
+        }
+        (None, _) => {}
+    }
+}
+
+/// A shard dying is as fatal as the single engine dying, all clients in it would be lost.
+fn exit_with<C>(report: Report<C>) -> ! {
+    eprintln!("{report:?}");
+    std::process::exit(1);
+}
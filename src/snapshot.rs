@@ -0,0 +1,56 @@
+use std::path::Path;
+
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_error::AppError, engine::EngineState};
+
+/// Bumped whenever the serialized shape of `EngineState` changes incompatibly.
+const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    version: u32,
+    state: S,
+}
+
+/// Write the entire engine state to `path` as json.
+///
+/// Written to a sibling temporary file then renamed over `path`,
+/// so a crash mid-write never leaves a half written snapshot behind.
+pub async fn write_snapshot(state: &EngineState, path: &Path) -> Result<(), Report<AppError>> {
+    let contents = serde_json::to_vec(&Snapshot {
+        version: SNAPSHOT_VERSION,
+        state,
+    })
+    .change_context(AppError)?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tokio::fs::write(&tmp_path, contents)
+        .await
+        .change_context(AppError)
+        .attach_with(|| format!("Writing snapshot to {tmp_path:?}"))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .change_context(AppError)
+        .attach_with(|| format!("Moving snapshot into place at {path:?}"))?;
+    Ok(())
+}
+
+pub async fn read_snapshot(path: &Path) -> Result<EngineState, Report<AppError>> {
+    let contents = tokio::fs::read(path)
+        .await
+        .change_context(AppError)
+        .attach_with(|| format!("Reading snapshot {path:?}"))?;
+    let snapshot: Snapshot<EngineState> = serde_json::from_slice(&contents)
+        .change_context(AppError)
+        .attach_with(|| format!("Parsing snapshot {path:?}"))?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(Report::new(AppError).attach(format!(
+            "Snapshot {path:?} has version {}, expected {SNAPSHOT_VERSION}",
+            snapshot.version
+        )));
+    }
+    Ok(snapshot.state)
+}
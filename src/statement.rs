@@ -0,0 +1,99 @@
+use error_stack::{Report, ResultExt};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    DecimalType,
+    app_error::AppError,
+    client::{AllClientsState, ClientId, HistoryKind},
+    ledger::Currency,
+    serialize_decimal,
+    transaction::{TransactionId, TransactionState},
+};
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum StatementFormat {
+    #[default]
+    Csv,
+    /// A single json array of the same rows.
+    Json,
+}
+
+/// One applied event in a client's statement, with the client's running balances after it.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq, serde::Deserialize))]
+pub struct StatementRow {
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(rename = "type")]
+    kind: HistoryKind,
+    #[serde(rename = "tx")]
+    txid: TransactionId,
+    /// Empty for the default asset, the balances are the client's in this asset.
+    #[cfg_attr(test, serde(default))]
+    currency: Currency,
+    #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
+    amount: DecimalType,
+    /// Fee charged with the event, negative when refunded.
+    #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(default, deserialize_with = "crate::deserialize_decimal"))]
+    fee: DecimalType,
+    /// The transaction's state now, not at the time of the event.
+    state: TransactionState,
+    #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
+    available: DecimalType,
+    #[serde(serialize_with = "serialize_decimal")]
+    #[cfg_attr(test, serde(deserialize_with = "crate::deserialize_decimal"))]
+    held: DecimalType,
+}
+
+/// Write the recorded history of `client_id`, or of every client ordered by id,
+/// each client's events in the order they were applied.
+pub async fn output_statement(
+    all_clients_state: &AllClientsState,
+    client_id: Option<ClientId>,
+    format: StatementFormat,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<(), Report<AppError>> {
+    let mut clients = all_clients_state
+        .iter()
+        .filter(|(id, _)| client_id.is_none_or(|client_id| **id == client_id))
+        .collect::<Vec<_>>();
+    clients.sort_by_key(|(id, _)| **id);
+
+    let rows = clients.into_iter().flat_map(|(id, client)| {
+        client.history().iter().map(|entry| StatementRow {
+            client_id: *id,
+            kind: entry.kind,
+            txid: entry.txid,
+            currency: entry.currency.clone(),
+            amount: entry.amount,
+            fee: entry.fee,
+            state: client
+                .transaction(entry.txid)
+                .expect("history only records applied transactions")
+                .state(),
+            available: entry.available,
+            held: entry.held,
+        })
+    });
+
+    match format {
+        StatementFormat::Csv => {
+            let mut wtr = csv_async::AsyncSerializer::from_writer(writer);
+            for row in rows {
+                wtr.serialize(&row).await.change_context(AppError)?;
+            }
+            wtr.flush().await.change_context(AppError)?;
+        }
+        StatementFormat::Json => {
+            let json =
+                serde_json::to_vec_pretty(&rows.collect::<Vec<_>>()).change_context(AppError)?;
+            writer.write_all(&json).await.change_context(AppError)?;
+            writer.flush().await.change_context(AppError)?;
+        }
+    }
+    Ok(())
+}
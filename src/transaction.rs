@@ -2,14 +2,36 @@ use std::collections::HashSet;
 
 use error_stack::Report;
 
-use crate::{DecimalType, engine_error::EngineError};
+use crate::{DecimalType, client::ClientId, engine_error::EngineError, ledger::Currency};
 
 pub type TransactionId = u32;
 
+/// Seconds since the unix epoch.
+pub type Timestamp = u64;
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     txid: TransactionId,
     kind: TransactionKind,
+    /// The asset the transaction moved, which its disputes act on too.
+    #[serde(default)]
+    currency: Currency,
     state: TransactionState,
+    /// Amount currently held under dispute, never more than `amount()`.
+    #[serde(default)]
+    disputed: DecimalType,
+    /// Amount charged back so far, by one or more partial chargebacks.
+    #[serde(default)]
+    charged_back: DecimalType,
+    /// Fee the client was charged for the transaction, refunded if it's charged back.
+    #[serde(default)]
+    fee: DecimalType,
+    /// When the transaction happened, `None` if it was applied before transactions had times.
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    /// When the transaction last went from `Normal` to `Disputed`.
+    #[serde(default)]
+    disputed_at: Option<Timestamp>,
 }
 
 impl Transaction {
@@ -17,6 +39,8 @@ impl Transaction {
         seen_txids: &mut HashSet<TransactionId>,
         txid: TransactionId,
         kind: TransactionKind,
+        currency: Currency,
+        timestamp: Timestamp,
     ) -> Result<Self, Report<EngineError>> {
         let is_new = seen_txids.insert(txid);
         if !is_new {
@@ -25,11 +49,38 @@ impl Transaction {
             Ok(Self {
                 txid,
                 kind,
+                currency,
                 state: TransactionState::Normal,
+                disputed: DecimalType::ZERO,
+                charged_back: DecimalType::ZERO,
+                fee: DecimalType::ZERO,
+                timestamp: Some(timestamp),
+                disputed_at: None,
             })
         }
     }
 
+    /// The receiving client's side of transfer `txid`, whose txid is checked with its sending side.
+    pub fn transfer_in(
+        txid: TransactionId,
+        from: ClientId,
+        amount: DecimalType,
+        currency: Currency,
+        timestamp: Timestamp,
+    ) -> Self {
+        Self {
+            txid,
+            kind: TransactionKind::TransferIn { amount, from },
+            currency,
+            state: TransactionState::Normal,
+            disputed: DecimalType::ZERO,
+            charged_back: DecimalType::ZERO,
+            fee: DecimalType::ZERO,
+            timestamp: Some(timestamp),
+            disputed_at: None,
+        }
+    }
+
     pub fn txid(&self) -> TransactionId {
         self.txid
     }
@@ -38,32 +89,143 @@ impl Transaction {
         &self.kind
     }
 
+    pub fn currency(&self) -> &Currency {
+        &self.currency
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
     pub fn amount(&self) -> DecimalType {
         match &self.kind {
             TransactionKind::Deposit { amount } => *amount,
             TransactionKind::Withdrawal { amount } => *amount,
+            TransactionKind::TransferOut { amount, .. } => *amount,
+            TransactionKind::TransferIn { amount, .. } => *amount,
         }
     }
 
-    pub fn mark_disputed(&mut self) -> Result<(), Report<EngineError>> {
-        self.check_state_is(TransactionState::Normal)?;
-        self.state = TransactionState::Disputed;
-        Ok(())
+    pub fn disputed(&self) -> DecimalType {
+        self.disputed
     }
 
-    pub fn mark_resolved(&mut self) -> Result<(), Report<EngineError>> {
-        self.check_state_is(TransactionState::Disputed)?;
-        self.state = TransactionState::Normal;
-        Ok(())
+    /// The fee a chargeback refunds: all of it on the first chargeback, even a partial one, then nothing.
+    pub fn chargeback_fee_refund(&self) -> DecimalType {
+        if self.is_charged_back() {
+            DecimalType::ZERO
+        } else {
+            self.fee
+        }
+    }
+
+    /// Whether any part has been charged back, even if another part is still disputed.
+    fn is_charged_back(&self) -> bool {
+        self.state == TransactionState::ChargedBack || self.charged_back != DecimalType::ZERO
+    }
+
+    pub fn set_fee(&mut self, fee: DecimalType) {
+        self.fee = fee;
+    }
+
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    pub fn disputed_at(&self) -> Option<Timestamp> {
+        self.disputed_at
+    }
+
+    /// The amount a dispute would hold: all of it if `requested` is `None`, otherwise `requested` on top of any
+    /// part already disputed, as long as the total disputed stays within the transaction amount.
+    pub fn dispute_amount(
+        &self,
+        requested: Option<DecimalType>,
+    ) -> Result<DecimalType, Report<EngineError>> {
+        match requested {
+            None => {
+                self.check_state_is(TransactionState::Normal)?;
+                Ok(self.amount())
+            }
+            Some(requested) => {
+                // Another part can be disputed while one is still open, but not once any is charged back:
+                if self.is_charged_back() {
+                    self.check_state_is(TransactionState::Normal)?;
+                }
+                // Can't overflow, `disputed` is between zero and the amount:
+                self.check_step_amount(requested, self.amount() - self.disputed, "disputed")
+            }
+        }
     }
 
-    pub fn mark_chargedback(&mut self) -> Result<(), Report<EngineError>> {
+    /// The amount a resolve or chargeback would act on: everything disputed if `requested` is `None`,
+    /// otherwise `requested` as long as that much is disputed.
+    pub fn settle_amount(
+        &self,
+        requested: Option<DecimalType>,
+        step: &'static str,
+    ) -> Result<DecimalType, Report<EngineError>> {
         self.check_state_is(TransactionState::Disputed)?;
-        self.state = TransactionState::ChargedBack;
-        Ok(())
+        match requested {
+            None => Ok(self.disputed),
+            Some(requested) => self.check_step_amount(requested, self.disputed, step),
+        }
+    }
+
+    /// A dispute of `amount`, as checked by `dispute_amount`, opened at `now`, which only becomes the dispute's
+    /// time if none was already open. Can't fail, so it's applied once the balances have moved.
+    pub fn mark_disputed(&mut self, amount: DecimalType, now: Timestamp) {
+        if self.state == TransactionState::Normal {
+            self.disputed_at = Some(now);
+        }
+        self.state = TransactionState::Disputed;
+        self.disputed += amount;
+    }
+
+    /// A resolve of `amount`, as checked by `settle_amount`. Only leaves `Disputed` once nothing is left disputed,
+    /// back to `Normal` unless some part was charged back.
+    pub fn mark_resolved(&mut self, amount: DecimalType) {
+        self.disputed -= amount;
+        self.settle_state();
+    }
+
+    /// A chargeback of `amount`, as checked by `settle_amount`. Any part still disputed stays held and `Disputed`,
+    /// so a later resolve or chargeback can settle it.
+    pub fn mark_chargedback(&mut self, amount: DecimalType) {
+        self.disputed -= amount;
+        self.charged_back += amount;
+        self.settle_state();
     }
 
-    fn check_state_is(&self, state: TransactionState) -> Result<(), Report<EngineError>> {
+    fn settle_state(&mut self) {
+        if self.disputed == DecimalType::ZERO {
+            self.state = if self.is_charged_back() {
+                TransactionState::ChargedBack
+            } else {
+                TransactionState::Normal
+            };
+        }
+    }
+
+    /// Only a positive amount, as a step of nothing would open or settle a dispute holding nothing.
+    fn check_step_amount(
+        &self,
+        requested: DecimalType,
+        limit: DecimalType,
+        step: &'static str,
+    ) -> Result<DecimalType, Report<EngineError>> {
+        if requested <= DecimalType::ZERO || requested > limit {
+            return Err(Report::from(EngineError::DisputeAmountMismatch {
+                txid: self.txid,
+                step,
+                requested,
+                limit,
+            }));
+        }
+        Ok(requested)
+    }
+
+    pub fn check_state_is(&self, state: TransactionState) -> Result<(), Report<EngineError>> {
         if self.state != state {
             return Err(Report::from(EngineError::TxNotInState {
                 txid: self.txid,
@@ -75,12 +237,38 @@ impl Transaction {
     }
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
 pub enum TransactionKind {
-    Deposit { amount: DecimalType },
-    Withdrawal { amount: DecimalType },
+    Deposit {
+        amount: DecimalType,
+    },
+    Withdrawal {
+        amount: DecimalType,
+    },
+    /// The sending side of a transfer to another client.
+    TransferOut {
+        amount: DecimalType,
+        to: ClientId,
+    },
+    /// The receiving side of a transfer from another client, which its disputes act on.
+    TransferIn {
+        amount: DecimalType,
+        from: ClientId,
+    },
+}
+
+impl TransactionKind {
+    /// Matches the record type of the input row that created the transaction.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit { .. } => "deposit",
+            TransactionKind::Withdrawal { .. } => "withdrawal",
+            TransactionKind::TransferOut { .. } | TransactionKind::TransferIn { .. } => "transfer",
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum TransactionState {
     Normal,
     Disputed,